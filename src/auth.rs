@@ -0,0 +1,334 @@
+use {
+    crate::{
+        config,
+        error::{ServerError, ServerResult},
+        PROG,
+    },
+    hmac::{Hmac, Mac},
+    logging::append_log,
+    sha2::Sha256,
+    std::{
+        collections::{BTreeSet, HashMap},
+        fs,
+        sync::{Mutex, OnceLock},
+        time::{SystemTime, UNIX_EPOCH},
+    },
+    system::create_hash,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Shared secrets by registration id, loaded once at startup
+static KEYS: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+// Nonces we have accepted recently, a request carrying one of them is a replay
+static NONCES: OnceLock<Mutex<NonceCache>> = OnceLock::new();
+
+// Bounded by auth.nonce_cache_size. A nonce is kept until its request's timestamp
+// falls out of the clock skew window, after that the request is refused for being
+// stale anyway. A timestamp ahead of our clock keeps its nonce longer. Dropping a
+// live one would let its request be replayed, so a full cache refuses new requests.
+#[derive(Default)]
+struct NonceCache {
+    seen: HashMap<String, u64>, // nonce to when it expires
+    expiry: BTreeSet<(u64, String)>,
+}
+
+impl NonceCache {
+    // Remembers the client's nonce until expires. False when it's remembered
+    // already, an error when the cache is full of nonces that haven't expired.
+    fn admit(
+        &mut self,
+        requestid: &str,
+        nonce: &str,
+        expires: u64,
+        now: u64,
+        capacity: usize,
+    ) -> ServerResult<bool> {
+        // Forgetting nonces whose requests are too old to be replayed
+        while let Some((expires, key)) = self.expiry.first() {
+            if *expires >= now {
+                break;
+            }
+            self.seen.remove(key);
+            self.expiry.pop_first();
+        }
+
+        let key: String = format!("{}/{}", requestid, nonce);
+        if self.seen.contains_key(&key) {
+            append_log(PROG, &format!("Replayed nonce from client {}", requestid));
+            return Ok(false);
+        }
+
+        if self.seen.len() >= capacity {
+            return Err(ServerError::RateLimited(format!(
+                "nonce cache is full, refused a request from client {}",
+                requestid
+            )));
+        }
+
+        self.seen.insert(key.clone(), expires);
+        self.expiry.insert((expires, key));
+        Ok(true)
+    }
+}
+
+// Reads the keys file named in the config. The file is a yaml map of
+// registration id to secret.
+pub fn init() -> Result<(), String> {
+    let keys_file: &str = &config::get().auth.keys_file;
+    let keys: HashMap<String, String> = match keys_file.is_empty() {
+        true => HashMap::new(),
+        false => {
+            let contents: String = fs::read_to_string(keys_file)
+                .map_err(|e| format!("Couldn't read keys file {}: {}", keys_file, e))?;
+            serde_yaml::from_str(&contents)
+                .map_err(|e| format!("Couldn't parse keys file {}: {}", keys_file, e))?
+        }
+    };
+
+    if let Some((requestid, _)) = keys.iter().find(|(_, secret)| secret.is_empty()) {
+        return Err(format!("Client {} has an empty secret", requestid));
+    }
+
+    append_log(PROG, &format!("Loaded keys for {} clients", keys.len()));
+    let _ = KEYS.set(keys);
+    Ok(())
+}
+
+// Clients send hex(HMAC-SHA256(secret, requestid + signed + ts + nonce)) in place
+// of the old unkeyed hash
+fn keyed_mac(secret: &str, requestid: &str, signed: &str, timestamp: &str, nonce: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes any key size");
+    mac.update(requestid.as_bytes());
+    mac.update(signed.as_bytes());
+    mac.update(timestamp.as_bytes());
+    mac.update(nonce.as_bytes());
+    mac
+}
+
+// Checks the integrity field of a request. signed is everything the client
+// signed, the command and its full payload. Keyed clients must also send a unix
+// timestamp and a nonce, so a captured request can't be sent again. Unkeyed
+// clients send neither and get no such protection. Errors when the request can't
+// be checked right now, see fresh.
+pub fn verify(
+    requestid: &str,
+    signed: &str,
+    integrity: &str,
+    timestamp: Option<&str>,
+    nonce: Option<&str>,
+) -> ServerResult<bool> {
+    let keys = KEYS.get().expect("Keys used before init");
+
+    match keys.get(requestid) {
+        Some(secret) => {
+            let (timestamp, nonce) = match (timestamp, nonce) {
+                (Some(timestamp), Some(nonce)) if !nonce.is_empty() => (timestamp, nonce),
+                _ => {
+                    append_log(PROG, &format!("Client {} sent no timestamp or nonce", requestid));
+                    return Ok(false);
+                }
+            };
+
+            let tag: Vec<u8> = match hex::decode(integrity) {
+                Ok(tag) => tag,
+                Err(_) => return Ok(false),
+            };
+
+            // verify_slice compares in constant time
+            let mac = keyed_mac(secret, requestid, signed, timestamp, nonce);
+            if mac.verify_slice(&tag).is_err() {
+                return Ok(false);
+            }
+
+            // Only authentic requests get this far, so forgeries can't fill the cache
+            fresh(requestid, timestamp, nonce)
+        }
+        // Clients without a key only get in when the old hash is still allowed
+        None => match config::get().auth.allow_unkeyed {
+            true => Ok(create_hash(&format!("{}{}", requestid, signed)) == integrity),
+            false => {
+                append_log(PROG, &format!("No key for client {}", requestid));
+                Ok(false)
+            }
+        },
+    }
+}
+
+// Whether the client has a shared secret, clients without one can only use the
+// old unkeyed hash
+pub fn keyed(requestid: &str) -> bool {
+    KEYS.get().expect("Keys used before init").contains_key(requestid)
+}
+
+// What can be checked before the rest of a request has been read: the client is
+// one we'd let in, and a keyed one sent a nonce and a timestamp inside the skew
+// window. verify still has to pass once the request is complete.
+pub fn plausible(requestid: &str, timestamp: Option<&str>, nonce: Option<&str>) -> bool {
+    let keys = KEYS.get().expect("Keys used before init");
+
+    match keys.contains_key(requestid) {
+        true => match (timestamp, nonce) {
+            (Some(timestamp), Some(nonce)) if !nonce.is_empty() => {
+                sent_time(requestid, timestamp).is_some()
+            }
+            _ => false,
+        },
+        false => config::get().auth.allow_unkeyed,
+    }
+}
+
+// The current time and the one the request was sent at, when that is within the
+// skew window of it
+fn sent_time(requestid: &str, timestamp: &str) -> Option<(u64, u64)> {
+    let now: u64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0);
+
+    let sent: u64 = match timestamp.parse::<u64>() {
+        Ok(sent) => sent,
+        Err(_) => {
+            append_log(PROG, &format!("Client {} sent a bad timestamp", requestid));
+            return None;
+        }
+    };
+
+    if now.abs_diff(sent) > config::get().auth.max_clock_skew {
+        append_log(
+            PROG,
+            &format!("Client {} sent a request {} seconds off our clock", requestid, now.abs_diff(sent)),
+        );
+        return None;
+    }
+
+    Some((now, sent))
+}
+
+// The timestamp has to be within the skew window and the nonce unseen. Errors
+// when the cache is full of nonces that could still be replayed.
+fn fresh(requestid: &str, timestamp: &str, nonce: &str) -> ServerResult<bool> {
+    let settings = &config::get().auth;
+    let (now, sent): (u64, u64) = match sent_time(requestid, timestamp) {
+        Some(times) => times,
+        None => return Ok(false),
+    };
+
+    let mut cache = NONCES
+        .get_or_init(|| Mutex::new(NonceCache::default()))
+        .lock()
+        .expect("Nonce cache lock poisoned");
+    // The request stays acceptable until its timestamp is skew behind our clock
+    cache.admit(
+        requestid,
+        nonce,
+        sent + settings.max_clock_skew,
+        now,
+        settings.nonce_cache_size,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::testing::{setup, CLIENT, SECRET},
+        std::sync::atomic::{AtomicU64, Ordering},
+    };
+
+    static NONCES: AtomicU64 = AtomicU64::new(0);
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Clock is before the epoch")
+            .as_secs()
+    }
+
+    // A nonce no other test uses, the cache is shared
+    fn nonce() -> String {
+        format!("auth{}", NONCES.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn mac(signed: &str, timestamp: &str, nonce: &str) -> String {
+        hex::encode(keyed_mac(SECRET, CLIENT, signed, timestamp, nonce).finalize().into_bytes())
+    }
+
+    fn check(signed: &str, integrity: &str, timestamp: &str, nonce: &str) -> ServerResult<bool> {
+        verify(CLIENT, signed, integrity, Some(timestamp), Some(nonce))
+    }
+
+    #[test]
+    fn valid_mac_is_accepted() {
+        setup();
+        let (timestamp, nonce): (String, String) = (now().to_string(), nonce());
+        let integrity: String = mac("Check/mail", &timestamp, &nonce);
+        assert!(check("Check/mail", &integrity, &timestamp, &nonce).unwrap());
+    }
+
+    #[test]
+    fn bad_mac_is_refused() {
+        setup();
+        let (timestamp, nonce): (String, String) = (now().to_string(), nonce());
+        let integrity: String = mac("Check/mail", &timestamp, &nonce);
+        assert!(!check("Check/news", &integrity, &timestamp, &nonce).unwrap());
+        assert!(!check("Check/mail", "not hex", &timestamp, &nonce).unwrap());
+        assert!(!check("Check/mail", &"00".repeat(32), &timestamp, &nonce).unwrap());
+    }
+
+    #[test]
+    fn stale_timestamp_is_refused() {
+        setup();
+        let skew: u64 = config::get().auth.max_clock_skew;
+        let (timestamp, nonce): (String, String) = ((now() - skew - 5).to_string(), nonce());
+        let integrity: String = mac("Check/mail", &timestamp, &nonce);
+        assert!(!check("Check/mail", &integrity, &timestamp, &nonce).unwrap());
+    }
+
+    #[test]
+    fn future_timestamp_is_refused() {
+        setup();
+        let skew: u64 = config::get().auth.max_clock_skew;
+        let (timestamp, nonce): (String, String) = ((now() + skew + 5).to_string(), nonce());
+        let integrity: String = mac("Check/mail", &timestamp, &nonce);
+        assert!(!check("Check/mail", &integrity, &timestamp, &nonce).unwrap());
+    }
+
+    #[test]
+    fn replayed_nonce_is_refused() {
+        setup();
+        let (timestamp, nonce): (String, String) = (now().to_string(), nonce());
+        let integrity: String = mac("Check/mail", &timestamp, &nonce);
+        assert!(check("Check/mail", &integrity, &timestamp, &nonce).unwrap());
+        assert!(!check("Check/mail", &integrity, &timestamp, &nonce).unwrap());
+    }
+
+    #[test]
+    fn full_cache_is_rate_limited() {
+        let mut cache: NonceCache = NonceCache::default();
+        let now: u64 = 1_760_000_000;
+        assert!(cache.admit(CLIENT, "n1", now + 300, now, 2).unwrap());
+        assert!(cache.admit(CLIENT, "n2", now + 300, now, 2).unwrap());
+
+        let refused: ServerError = cache.admit(CLIENT, "n3", now + 300, now, 2).unwrap_err();
+        assert!(matches!(refused, ServerError::RateLimited(_)));
+        assert_eq!(refused.status().code(), 429);
+        // Expired nonces make room again
+        assert!(cache.admit(CLIENT, "n3", now + 700, now + 400, 2).unwrap());
+    }
+
+    // A request sent ahead of our clock is still inside the window after arrival
+    // plus skew, so its nonce has to outlive that
+    #[test]
+    fn future_timestamps_keep_their_nonce() {
+        let mut cache: NonceCache = NonceCache::default();
+        let (skew, now): (u64, u64) = (2, 1_760_000_000);
+        let expires: u64 = now + 2 + skew;
+
+        assert!(cache.admit("mailer-01", "n1", expires, now, 10).unwrap());
+        assert!(!cache.admit("mailer-01", "n1", expires, now + 3, 10).unwrap());
+        assert!(!cache.admit("mailer-01", "n1", expires, expires, 10).unwrap());
+        assert!(cache.admit("mailer-01", "n1", expires + 1 + skew, expires + 1, 10).unwrap());
+    }
+}
@@ -0,0 +1,487 @@
+use crate::{
+    auth,
+    error::{ServerError, ServerResult},
+    skel::{ChannelName, Integrity, Payload, Request, RequestCode, RequestData, Responses, StatCode},
+    PROG,
+};
+use logging::append_log;
+
+// Layout is in docs/binary-protocol.md, keep the two in step.
+// The magic byte can't start UTF-8 text, so it never collides with the other
+// encodings when the first frame is sniffed.
+pub const MAGIC: u8 = 0xB1;
+pub const FORMAT_VERSION: u8 = 1;
+
+const REQUEST_HEADER: usize = 50;
+const RESPONSE_HEADER: usize = 13;
+const MAC_LENGTH: usize = 32;
+
+// Field types
+const FIELD_TEXT: u8 = 0x01; // UTF-8, passed to the handler as is
+const FIELD_BYTES: u8 = 0x02; // raw bytes, passed to the handler hex encoded
+
+// Response flags
+const FLAG_PUSH: u8 = 0x01; // pushed for a subscription, the tag holds the channel
+
+// Opcodes map onto the same commands the text format names
+fn command(opcode: u8) -> Option<&'static str> {
+    match opcode {
+        0x01 => Some("Hello"),
+        0x02 => Some("CreateChannel"),
+        0x03 => Some("DeleteChannel"),
+        0x04 => Some("RegisterChannel"),
+        0x05 => Some("Store"),
+        0x06 => Some("Check"),
+        0x07 => Some("Ack"),
+        0x08 => Some("Subscribe"),
+        0x09 => Some("Unsubscribe"),
+        0x0A => Some("Nack"),
+        0x0B => Some("DeadLetter"),
+        0x0C => Some("Replay"),
+        _ => None,
+    }
+}
+
+// Bounds checked reads over a frame
+struct Reader<'a> {
+    frame: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> ServerResult<&'a [u8]> {
+        let end: Option<usize> = self.position.checked_add(length);
+        match end.and_then(|end| self.frame.get(self.position..end)) {
+            Some(bytes) => {
+                self.position += length;
+                Ok(bytes)
+            }
+            None => Err(ServerError::Malformed(format!(
+                "binary frame ends at byte {}, wanted {} more from {}",
+                self.frame.len(),
+                length,
+                self.position
+            ))),
+        }
+    }
+
+    fn u8(&mut self) -> ServerResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> ServerResult<u16> {
+        let bytes: &[u8] = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> ServerResult<u32> {
+        let bytes: &[u8] = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> ServerResult<u64> {
+        let mut bytes: [u8; 8] = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn text(&mut self, length: usize, what: &str) -> ServerResult<String> {
+        String::from_utf8(self.take(length)?.to_vec())
+            .map_err(|_| ServerError::Malformed(format!("{} isn't UTF-8", what)))
+    }
+}
+
+pub fn parse_request(frame: &[u8]) -> ServerResult<Request> {
+    if frame.len() < REQUEST_HEADER {
+        return Err(ServerError::Malformed(format!(
+            "binary request needs a {} byte header, got {} bytes",
+            REQUEST_HEADER,
+            frame.len()
+        )));
+    }
+
+    let mut reader: Reader = Reader { frame, position: 0 };
+    let _magic: u8 = reader.u8()?;
+    let version: u8 = reader.u8()?;
+    if version != FORMAT_VERSION {
+        return Err(ServerError::Malformed(format!(
+            "binary format version {} isn't supported",
+            version
+        )));
+    }
+    let opcode: u8 = reader.u8()?;
+    let command: &str = match command(opcode) {
+        Some(command) => command,
+        None => return Err(ServerError::UnknownCommand(format!("opcode 0x{:02x}", opcode))),
+    };
+    let _flags: u8 = reader.u8()?;
+    let timestamp: u64 = reader.u64()?;
+    let mac: &[u8] = reader.take(MAC_LENGTH)?;
+    let requestid_length: usize = reader.u16()? as usize;
+    let nonce_length: usize = reader.u8()? as usize;
+    let tag_length: usize = reader.u8()? as usize;
+    let field_count: u16 = reader.u16()?;
+
+    let requestid: String = reader.text(requestid_length, "request id")?;
+    let nonce: String = reader.text(nonce_length, "nonce")?;
+    let tag: Option<String> = match tag_length {
+        0 => None,
+        _ => Some(reader.text(tag_length, "tag")?),
+    };
+
+    let fields_start: usize = reader.position;
+    let mut args: Vec<String> = Vec::with_capacity(field_count as usize);
+    for _ in 0..field_count {
+        let field_type: u8 = reader.u8()?;
+        let length: usize = reader.u32()? as usize;
+        match field_type {
+            FIELD_TEXT => args.push(reader.text(length, "text field")?),
+            FIELD_BYTES => args.push(hex::encode(reader.take(length)?)),
+            other => {
+                return Err(ServerError::Malformed(format!(
+                    "unknown binary field type 0x{:02x}",
+                    other
+                )))
+            }
+        }
+    }
+
+    if reader.position != frame.len() {
+        return Err(ServerError::Malformed(format!(
+            "{} trailing bytes after the last field",
+            frame.len() - reader.position
+        )));
+    }
+
+    // The fields are signed as they were sent, type and length bytes included, so
+    // where one field ends and the next starts can't be moved. The tag is signed
+    // the way a text field would carry it: Command/<hex of the fields>,tag=7
+    let mut signed: String = match args.is_empty() {
+        true => command.to_string(),
+        false => format!("{}/{}", command, hex::encode(&frame[fields_start..])),
+    };
+    if let Some(tag) = &tag {
+        signed.push_str(&format!(",tag={}", tag));
+    }
+    // Binary connections need a key, the old unkeyed hash has no replay protection
+    // and there's no older binary client it would have to be kept for
+    let integrity: bool = match auth::keyed(&requestid) {
+        true => auth::verify(
+            &requestid,
+            &signed,
+            &hex::encode(mac),
+            Some(&timestamp.to_string()),
+            Some(&nonce),
+        )?,
+        false => {
+            append_log(PROG, &format!("No key for binary client {}", requestid));
+            false
+        }
+    };
+
+    match args.is_empty() {
+        true => Ok(Request::Code(RequestCode {
+            command: command.to_string(),
+            requestid,
+            integrity,
+            tag,
+        })),
+        false => Ok(Request::Data(RequestData {
+            command: command.to_string(),
+            args,
+            requestid,
+            integrity,
+            tag,
+        })),
+    }
+}
+
+pub fn render(response: &Responses, tag: Option<&str>) -> Vec<u8> {
+    frame(response, tag.unwrap_or(""), 0)
+}
+
+pub fn render_push(channel: &ChannelName, response: &Responses) -> Vec<u8> {
+    frame(response, channel.as_str(), FLAG_PUSH)
+}
+
+fn frame(response: &Responses, tag: &str, flags: u8) -> Vec<u8> {
+    let (status, reason, integrity, id, data): (&StatCode, &str, &str, &str, Vec<u8>) =
+        match response {
+            Responses::Code(code) => (code, "", "", "", Vec::new()),
+            Responses::Reason(code, reason) => (code, reason.as_str(), "", "", Vec::new()),
+            Responses::Data(code, Payload::Data(data, Integrity::Hash(hash))) => {
+                (code, "", hash.as_str(), "", data.as_bytes().to_vec())
+            }
+            // Stored messages go out as the raw bytes the producer sent
+            Responses::Data(code, Payload::Hex(data, Integrity::Hash(hash), id)) => {
+                let raw: Vec<u8> = hex::decode(data).unwrap_or_else(|_| data.as_bytes().to_vec());
+                (code, "", hash.as_str(), id.as_str(), raw)
+            }
+        };
+    // Tags arrive with a one byte length, channel names are shorter still, reasons
+    // are short names, hashes hex digests and ids uuids, so all of them fit their
+    // one byte lengths
+    let mut frame: Vec<u8> = Vec::with_capacity(
+        RESPONSE_HEADER + tag.len() + reason.len() + integrity.len() + id.len() + data.len(),
+    );
+    frame.push(MAGIC);
+    frame.push(FORMAT_VERSION);
+    frame.extend_from_slice(&status.code().to_be_bytes());
+    frame.push(tag.len() as u8);
+    frame.push(reason.len() as u8);
+    frame.push(integrity.len() as u8);
+    frame.push(id.len() as u8);
+    frame.push(flags);
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(tag.as_bytes());
+    frame.extend_from_slice(reason.as_bytes());
+    frame.extend_from_slice(integrity.as_bytes());
+    frame.extend_from_slice(id.as_bytes());
+    frame.extend_from_slice(&data);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            phrasing_request,
+            testing::{setup, CLIENT, SECRET},
+        },
+        hmac::{Hmac, Mac},
+        sha2::Sha256,
+        std::{
+            sync::atomic::{AtomicU64, Ordering},
+            time::{SystemTime, UNIX_EPOCH},
+        },
+    };
+
+    static NONCES: AtomicU64 = AtomicU64::new(0);
+
+    enum Field {
+        Text(&'static str),
+        Bytes(&'static [u8]),
+    }
+
+    // A timestamp, nonce and the MAC over them
+    struct Signature {
+        timestamp: u64,
+        nonce: String,
+        mac: Vec<u8>,
+    }
+
+    // What a parsed request comes down to, Request has no PartialEq
+    type Summary = (String, Option<Vec<String>>, String, bool, Option<String>);
+
+    fn sign(signed: &str) -> Signature {
+        let timestamp: u64 = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Clock is before the epoch")
+            .as_secs();
+        let nonce: String = format!("test{}", NONCES.fetch_add(1, Ordering::Relaxed));
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).expect("Any key size");
+        mac.update(CLIENT.as_bytes());
+        mac.update(signed.as_bytes());
+        mac.update(timestamp.to_string().as_bytes());
+        mac.update(nonce.as_bytes());
+        Signature {
+            timestamp,
+            nonce,
+            mac: mac.finalize().into_bytes().to_vec(),
+        }
+    }
+
+    fn encode_fields(fields: &[Field]) -> Vec<u8> {
+        let mut encoded: Vec<u8> = Vec::new();
+        for field in fields {
+            let (field_type, value): (u8, &[u8]) = match field {
+                Field::Text(text) => (FIELD_TEXT, text.as_bytes()),
+                Field::Bytes(bytes) => (FIELD_BYTES, bytes),
+            };
+            encoded.push(field_type);
+            encoded.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            encoded.extend_from_slice(value);
+        }
+        encoded
+    }
+
+    fn binary_signed(command: &str, fields: &[Field], tag: &str) -> String {
+        let mut signed: String = match fields.is_empty() {
+            true => command.to_string(),
+            false => format!("{}/{}", command, hex::encode(encode_fields(fields))),
+        };
+        if !tag.is_empty() {
+            signed.push_str(&format!(",tag={}", tag));
+        }
+        signed
+    }
+
+    fn binary_frame(opcode: u8, fields: &[Field], tag: &str, signature: &Signature) -> Vec<u8> {
+        let mut frame: Vec<u8> = vec![MAGIC, FORMAT_VERSION, opcode, 0];
+        frame.extend_from_slice(&signature.timestamp.to_be_bytes());
+        frame.extend_from_slice(&signature.mac);
+        frame.extend_from_slice(&(CLIENT.len() as u16).to_be_bytes());
+        frame.push(signature.nonce.len() as u8);
+        frame.push(tag.len() as u8);
+        frame.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+        frame.extend_from_slice(CLIENT.as_bytes());
+        frame.extend_from_slice(signature.nonce.as_bytes());
+        frame.extend_from_slice(tag.as_bytes());
+        frame.extend_from_slice(&encode_fields(fields));
+        frame
+    }
+
+    fn binary_request(opcode: u8, fields: &[Field], tag: &str) -> Vec<u8> {
+        let command: &str = command(opcode).expect("Known opcode");
+        binary_frame(opcode, fields, tag, &sign(&binary_signed(command, fields, tag)))
+    }
+
+    // The text frame the binary request stands for, byte fields hex encoded
+    fn text_request(command: &str, fields: &[Field], tag: &str) -> String {
+        let args: Vec<String> = fields
+            .iter()
+            .map(|field| match field {
+                Field::Text(text) => text.to_string(),
+                Field::Bytes(bytes) => hex::encode(bytes),
+            })
+            .collect();
+        let mut signed: String = match args.is_empty() {
+            true => command.to_string(),
+            false => format!("{}/{}", command, args.join("_")),
+        };
+        if !tag.is_empty() {
+            signed.push_str(&format!(",tag={}", tag));
+        }
+        let signature: Signature = sign(&signed);
+        let (data, tag_field): (&str, &str) = signed.split_once(',').unwrap_or((&signed, ""));
+        let mut frame: String = format!("{},{},{}", data, CLIENT, hex::encode(&signature.mac));
+        if !tag_field.is_empty() {
+            frame.push_str(&format!(",{}", tag_field));
+        }
+        frame.push_str(&format!(",ts={},nonce={}", signature.timestamp, signature.nonce));
+        frame
+    }
+
+    fn summary(request: Request) -> Summary {
+        match request {
+            Request::Code(code) => (code.command, None, code.requestid, code.integrity, code.tag),
+            Request::Data(data) => {
+                (data.command, Some(data.args), data.requestid, data.integrity, data.tag)
+            }
+        }
+    }
+
+    fn store_fields() -> [Field; 4] {
+        [Field::Text("mail"), Field::Text("plain"), Field::Bytes(b"hi"), Field::Text("6869")]
+    }
+
+    #[test]
+    fn every_opcode_matches_its_text_form() {
+        setup();
+        let requests: Vec<(u8, Vec<Field>)> = vec![
+            (0x01, vec![]),
+            (0x01, vec![Field::Text("1"), Field::Text("reasons")]),
+            (0x02, vec![Field::Text("mail")]),
+            (0x03, vec![Field::Text("mail")]),
+            (0x04, vec![Field::Text("mail"), Field::Text("workers")]),
+            (0x05, store_fields().into()),
+            (0x06, vec![Field::Text("mail"), Field::Text("5000")]),
+            (0x07, vec![Field::Text("mail"), Field::Text("3f2a")]),
+            (0x08, vec![Field::Text("mail"), Field::Text("16")]),
+            (0x09, vec![Field::Text("mail")]),
+            (
+                0x0A,
+                vec![
+                    Field::Text("mail"),
+                    Field::Text("3f2a"),
+                    Field::Text("1000"),
+                    Field::Text("bounced"),
+                ],
+            ),
+            (0x0B, vec![Field::Text("mail"), Field::Text("maildead"), Field::Text("5")]),
+            (0x0C, vec![Field::Text("mail")]),
+        ];
+
+        for (opcode, fields) in &requests {
+            let command: &str = command(*opcode).expect("Known opcode");
+            for tag in ["", "7"] {
+                let binary = match parse_request(&binary_request(*opcode, fields, tag)) {
+                    Ok(request) => summary(request),
+                    Err(e) => panic!("Binary {} didn't parse: {}", command, e),
+                };
+                let text = match phrasing_request(text_request(command, fields, tag)) {
+                    Ok(request) => summary(request),
+                    Err(e) => panic!("Text {} didn't parse: {}", command, e),
+                };
+                assert!(binary.3, "Binary {} failed verification", command);
+                assert_eq!(binary, text, "Opcode 0x{:02x} tagged {:?}", opcode, tag);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_frames_are_malformed() {
+        setup();
+        let frame: Vec<u8> = binary_request(0x05, &store_fields(), "7");
+        for length in 0..frame.len() {
+            assert!(
+                matches!(parse_request(&frame[..length]), Err(ServerError::Malformed(_))),
+                "Frame cut to {} of {} bytes wasn't refused",
+                length,
+                frame.len()
+            );
+        }
+    }
+
+    #[test]
+    fn oversized_lengths_are_malformed() {
+        setup();
+        let frame: Vec<u8> = binary_request(0x05, &store_fields(), "7");
+        let nonce_length: usize = frame[46] as usize;
+        let first_field: usize = REQUEST_HEADER + CLIENT.len() + nonce_length + 1;
+
+        // Request id length, field count and the first field's length
+        let oversized: [(usize, &[u8]); 3] = [
+            (44, &[0xFF, 0xFF]),
+            (48, &[0xFF, 0xFF]),
+            (first_field + 1, &[0xFF, 0xFF, 0xFF, 0xFF]),
+        ];
+        for (offset, bytes) in oversized {
+            let mut bad: Vec<u8> = frame.clone();
+            bad[offset..offset + bytes.len()].copy_from_slice(bytes);
+            assert!(
+                matches!(parse_request(&bad), Err(ServerError::Malformed(_))),
+                "Oversized length at byte {} wasn't refused",
+                offset
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_opcode_is_refused() {
+        setup();
+        let fields: [Field; 1] = [Field::Text("mail")];
+        let signature: Signature = sign(&binary_signed("Bogus", &fields, ""));
+        let frame: Vec<u8> = binary_frame(0x7F, &fields, "", &signature);
+        assert!(matches!(parse_request(&frame), Err(ServerError::UnknownCommand(_))));
+    }
+
+    // mail_a + b and mail + a_b are the same text request, but not the same fields
+    #[test]
+    fn fields_cant_be_regrouped() {
+        setup();
+        let signed: [Field; 2] = [Field::Text("mail_a"), Field::Text("b")];
+        let sent: [Field; 2] = [Field::Text("mail"), Field::Text("a_b")];
+        let signature: Signature = sign(&binary_signed("Check", &signed, ""));
+
+        match parse_request(&binary_frame(0x06, &sent, "", &signature)) {
+            Ok(request) => assert!(!summary(request).3, "Regrouped fields passed verification"),
+            Err(e) => panic!("Regrouped request didn't parse: {}", e),
+        }
+        match parse_request(&binary_frame(0x06, &signed, "", &signature)) {
+            Ok(request) => assert!(summary(request).3, "Request as signed failed verification"),
+            Err(e) => panic!("Request as signed didn't parse: {}", e),
+        }
+    }
+}
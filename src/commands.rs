@@ -1,32 +1,41 @@
 use {
-    crate::database::create_conn,
+    crate::config,
+    crate::error::{ServerError, ServerResult},
     crate::functions::{
-        check_permission, create_message_table, create_permission_table, no_handel, no_permission,
-        payload_integrity, sec_fault, send_ack_dr, send_ack_ds, send_ack_ok,
+        ack_dr, ack_message, ack_ok, channel_name, check_permission, expect_fields,
+        payload_integrity,
     },
-    crate::skel::Message,
+    crate::notify,
+    crate::skel::{ChannelName, Message, Responses},
+    crate::storage::{self, with_storage, Fetched},
     crate::PROG,
     logging::append_log,
-    mysql::{prelude::Queryable, PooledConn},
-    std::net::TcpStream,
+    std::time::Duration,
     system::create_hash,
+    tokio::{
+        sync::watch,
+        time::{timeout_at, Instant},
+    },
+    uuid::Uuid,
 };
 
-pub fn complex_processor(command: &str, data: String, register_id: String, tcp_stream: &TcpStream) {
+pub async fn complex_processor(
+    command: &str,
+    args: Vec<String>,
+    register_id: String,
+) -> ServerResult<Responses> {
     match command {
-        "RegisterChannel" => register_channel(&data, register_id, tcp_stream),
-        "DeleteChannel" => delete_channel(&data, tcp_stream),
-        "CreateChannel" => create_channel(&data, register_id, tcp_stream),
-        "Store" => match payload_integrity(&data) {
-            true => store(data, tcp_stream, register_id),
-            false => no_handel(tcp_stream),
-        },
+        "RegisterChannel" => register_channel(&args, register_id).await,
+        "DeleteChannel" => delete_channel(&args).await,
+        "CreateChannel" => create_channel(&args, register_id).await,
+        "Store" => store(&args, register_id).await,
         "Check" => {
-            check_msg(&data, &register_id, tcp_stream);
+            let response: ServerResult<Responses> = check_msg(&args, &register_id).await;
             append_log(
                 PROG,
                 &format!("Client {} has checked meessages", register_id),
             );
+            response
         }
         "Ack" => {
             append_log(
@@ -34,262 +43,386 @@ pub fn complex_processor(command: &str, data: String, register_id: String, tcp_s
                 &format!(
                     "Ack recived by {}, with this data hash: {}",
                     register_id,
-                    create_hash(&data)
+                    create_hash(&args.join("_"))
                 ),
             );
-            ack_msg(&data, register_id, tcp_stream);
+            ack_msg(&args, register_id).await
         }
-        &_ => no_handel(tcp_stream),
+        "Nack" => nack_msg(&args, register_id).await,
+        "DeadLetter" => dead_letter(&args, register_id).await,
+        "Replay" => replay(&args, register_id).await,
+        &_ => Err(ServerError::UnknownCommand(command.to_string())),
     }
 }
 
-pub fn simple_processor(command: &str, _: String, tcp_stream: &TcpStream) {
+pub async fn simple_processor(command: &str, _: String) -> ServerResult<Responses> {
     match command {
-        &_ => no_handel(tcp_stream),
+        &_ => Err(ServerError::UnknownCommand(command.to_string())),
     }
 }
 
-fn create_channel(data: &str, _reg: String, tcp_stream: &TcpStream) {
-    let magic: (bool, bool) = (create_message_table(data), create_permission_table(data));
+async fn create_channel(args: &[String], _reg: String) -> ServerResult<Responses> {
+    let channel: ChannelName = channel_name(&expect_fields(args, 1)?[0])?;
 
-    let result: bool = match magic {
-        (true, true) => true,
-        (true, false) => {
-            append_log(
-                PROG,
-                &format!("Unable to create permission table for {}", data),
-            );
-            false
-        }
-        (false, true) => {
-            append_log(
-                PROG,
-                &format!("Unable to create message table for {}", data),
-            );
-            false
-        }
-        (false, false) => {
-            append_log(
-                PROG,
-                &format!("{}: Database could not be interacted with", data),
-            );
-            false
+    with_storage(move |storage| storage.create_channel(&channel)).await?;
+    Ok(ack_dr())
+}
+
+// RegisterChannel/channel gets every message, RegisterChannel/channel_group shares
+// them with the rest of the group, each message going to one member
+async fn register_channel(args: &[String], reg: String) -> ServerResult<Responses> {
+    let (channel, group): (ChannelName, Option<String>) = match args {
+        [channel] => (channel_name(channel)?, None),
+        [channel, group] => (channel_name(channel)?, Some(group_name(group)?)),
+        args => {
+            return Err(ServerError::Malformed(format!(
+                "expected 1 or 2 fields in the payload, got {}",
+                args.len()
+            )))
         }
     };
 
-    match result {
-        true => send_ack_dr(tcp_stream),
-        false => no_handel(tcp_stream),
+    let uuid: String = reg.clone();
+    let joined: Option<String> = group.clone();
+    with_storage(move |storage| storage.register(&channel, &uuid, joined.as_deref())).await?;
+    match group {
+        Some(group) => append_log(PROG, &format!("Client {} registered in {}", reg, group)),
+        None => append_log(PROG, &format!("Client {} registered", reg)),
     }
+    Ok(ack_dr())
 }
 
-fn register_channel(data: &str, reg: String, tcp_stream: &TcpStream) {
-    let mut conn = create_conn();
-    let result: bool = match conn.query_drop(format!(
-        r"INSERT INTO Artisan_Messenger.{}_permission (uuid) VALUES ('{}')",
-        data, reg
-    )) {
-        Ok(_) => {
-            append_log(PROG, &format!("Client {} registered", reg));
-            true
-        }
-        Err(e) => {
-            append_log(
-                PROG,
-                &format!("Registering {} on {}_permission, FAILED: {}", reg, data, e),
-            );
-            false
-        }
+// Group names follow the channel name rules, they end up in the same kind of column
+fn group_name(group: &str) -> ServerResult<String> {
+    match ChannelName::parse(group) {
+        Some(name) => Ok(name.as_str().to_string()),
+        None => Err(ServerError::Malformed(format!(
+            "invalid group name {:?}",
+            group
+        ))),
+    }
+}
+
+async fn delete_channel(args: &[String]) -> ServerResult<Responses> {
+    let channel: ChannelName = channel_name(&expect_fields(args, 1)?[0])?;
+
+    let target: ChannelName = channel.clone();
+    with_storage(move |storage| storage.delete_channel(&target)).await?;
+    notify::forget(&channel);
+    append_log(
+        PROG,
+        &format!("The channel {} has been dropped sucessfully", channel),
+    );
+    Ok(ack_ok())
+}
+
+async fn store(args: &[String], reg_id: String) -> ServerResult<Responses> {
+    let message: &[String] = expect_fields(args, 4)?;
+    if !payload_integrity(message) {
+        return Err(ServerError::Integrity(String::from(
+            "stored message doesn't match its hash",
+        )));
+    }
+
+    let channel: ChannelName = channel_name(&message[0])?;
+    let message_type: String = message[1].to_owned();
+    let encoded_message: String = message[2].to_owned();
+    let message_hash: String = message[3].to_owned();
+
+    // We decode before writing so bad hex never reaches the database
+    match hex::decode(&encoded_message) {
+        Ok(bytes) => match String::from_utf8(bytes) {
+            Ok(_) => (),
+            Err(e) => return Err(ServerError::Malformed(format!("Message isn't utf8: {}", e))),
+        },
+        Err(e) => return Err(ServerError::Malformed(format!("Message isn't hex: {}", e))),
     };
 
-    match result {
-        true => send_ack_dr(tcp_stream),
-        false => no_handel(tcp_stream),
+    // Identical bodies are still separate messages, each gets its own id
+    let new_message: Message = Message {
+        uuid: Uuid::new_v4().to_string(),
+        message_type,
+        message: encoded_message,
+        hash: message_hash,
+        attempts: 0,
     };
+
+    // Check permissions and write to database
+    check_permission(&channel, &reg_id).await?;
+    let target: ChannelName = channel.clone();
+    with_storage(move |storage| storage.enqueue(&target, &new_message)).await?;
+    notify::wake(&channel);
+    append_log(PROG, "Message Saved");
+    Ok(ack_dr())
 }
 
-fn delete_channel(data: &str, tcp_stream: &TcpStream) {
-    let mut conn = create_conn();
-    let drop_message: String = format!("DROP TABLE Artisan_Messenger.{}", data);
-    let drop_permission: String = format!("DROP TABLE Artisan_Messenger.{}_permission", data);
+// Check/channel answers straight away, Check/channel_waitms holds the request until
+// a message arrives or the wait is over
+async fn check_msg(args: &[String], reg: &str) -> ServerResult<Responses> {
+    let (channel, wait): (ChannelName, Duration) = match args {
+        [channel] => (channel_name(channel)?, Duration::ZERO),
+        [channel, wait] => (channel_name(channel)?, check_wait(wait)?),
+        args => {
+            return Err(ServerError::Malformed(format!(
+                "expected 1 or 2 fields in the payload, got {}",
+                args.len()
+            )))
+        }
+    };
+    check_permission(&channel, reg).await?;
 
-    let drop_tuple = (
-        conn.query_drop(drop_message),
-        conn.query_drop(drop_permission),
-    );
+    let deadline: Instant = Instant::now() + wait;
+    // Watching before the read, so a store landing between it and the wait still wakes us
+    let mut changes: Option<watch::Receiver<u64>> =
+        (!wait.is_zero()).then(|| notify::watch(&channel));
+    let mut shutdown: watch::Receiver<bool> = notify::shutdown();
 
-    let result: bool = match drop_tuple {
-        (Ok(_), Ok(_)) => {
-            append_log(
-                PROG,
-                &format!("The channel {} has been dropped sucessfully", data),
-            );
-            true
+    let visibility: Duration = storage::visibility();
+    loop {
+        // read the latest message in the database, it stays ours until the lease runs out
+        let target: ChannelName = channel.clone();
+        let consumer: String = reg.to_string();
+        let fetched: Fetched =
+            with_storage(move |storage| storage.fetch_many(&target, &consumer, 1, visibility))
+                .await?;
+        if let Some(dead_letter) = &fetched.dead_letter {
+            notify::wake(dead_letter);
         }
-        (Ok(_), Err(_)) => {
-            append_log(
-                PROG,
-                &format!("The channel {} has been partially dropped", data),
-            );
-            false
+        if let Some(message) = fetched.messages.into_iter().next() {
+            notify::wake_after(&channel, visibility);
+            return deliverable(message);
         }
-        (Err(_), Ok(_)) => {
-            append_log(
-                PROG,
-                &format!("The channel {} has been partially dropped", data),
-            );
-            false
-        }
-        #[allow(non_snake_case)]
-        (Err(E1), Err(E2)) => {
-            append_log(
-                PROG,
-                &format!(
-                    "The channel {} could not be dropped: \n {} \n {}",
-                    data, E1, E2
-                ),
-            );
-            false
+
+        let waiting: &mut watch::Receiver<u64> = match changes.as_mut() {
+            Some(waiting) => waiting,
+            None => return Ok(ack_ok()), // no data
+        };
+        tokio::select! {
+            changed = timeout_at(deadline, waiting.changed()) => match changed {
+                Ok(Ok(())) => (),
+                // Forgotten with the channel, the next read says whether it's really gone
+                Ok(Err(_)) => *waiting = notify::watch(&channel),
+                Err(_) => return Ok(ack_ok()), // waited long enough
+            },
+            // The server is stopping, answering empty now lets the connection drain
+            _ = shutdown.wait_for(|stopping| *stopping) => return Ok(ack_ok()),
         }
-    };
+    }
+}
 
-    match result {
-        true => send_ack_ok(tcp_stream),
-        false => no_handel(tcp_stream),
-    };
+// Longer waits than the server allows are cut down to its limit
+fn check_wait(wait: &str) -> ServerResult<Duration> {
+    let limit: u64 = config::get().limits.max_check_wait;
+    match wait.parse::<u64>() {
+        Ok(wait) => Ok(Duration::from_millis(wait.min(limit))),
+        Err(_) => Err(ServerError::Malformed(format!(
+            "wait must be a number of milliseconds, got {:?}",
+            wait
+        ))),
+    }
 }
 
-fn store(data: String, tcp_stream: &TcpStream, reg_id: String) {
-    let mut conn: PooledConn = create_conn();
-    let message: Vec<String> = data.split('_').map(|s| s.to_string()).collect();
+// A stored message only goes out if it still matches the hash it was stored with
+pub fn deliverable(message: Message) -> ServerResult<Responses> {
+    if message.attempts > 1 {
+        append_log(
+            PROG,
+            &format!("Handing out {} again, attempt {}", message.uuid, message.attempts),
+        );
+    }
+    let message_data: String = message.message;
 
-    let channel: String = message[0].to_owned();
-    let message_type: String = message[1].to_owned();
-    let encoded_message: String = message[2].to_owned();
-    let message_hash: String = message[3].to_owned(); // will be used as the uuid in the database. messages will need a timestamp in them by default
-
-    #[allow(unused)] // We use this to allow error checking before writing to the database
-    let decoded_message: String = match hex::decode(&encoded_message) {
-        Ok(data) => {
-            let hex_decoded_data: String = match String::from_utf8(data) {
-                Ok(string) => string,
-                Err(e) => {
-                    append_log(PROG, &format!("Error decoded hex string: {}", e));
-                    panic!("{}", e);
-                }
-            };
-            format!("{}", hex_decoded_data)
-        }
-        Err(e) => {
-            append_log(PROG, &format!("Error decoded hex string: {}", e));
-            panic!("{}", e);
-        }
-    };
+    let message_integrity: String = message.hash;
+    let new_integrity: String = create_hash(&message_data);
 
-    let commit_query: String = format!(
-        r"INSERT INTO Artisan_Messenger.{} (uuid, message_type, message) VALUES ( '{}', '{}', '{}' )",
-        &channel, message_hash, message_type, &encoded_message
-    );
+    match new_integrity == message_integrity {
+        true => Ok(ack_message(message_data, message.uuid)),
+        false => Err(ServerError::Integrity(format!(
+            "stored message {} doesn't match its hash",
+            message.uuid
+        ))),
+    }
+}
 
-    // Check permissions and write to database
-    match check_permission(&channel, &reg_id) {
-        true => match conn.query_drop(commit_query) {
-            Ok(_) => {
-                append_log(PROG, "Message Saved");
-                send_ack_dr(tcp_stream);
+// Ack/channel_id, the id is the one a message was handed out with. Acking one the
+// client isn't waiting for answers 406 when it's unknown and 407 when it was acked.
+async fn ack_msg(args: &[String], reg_id: String) -> ServerResult<Responses> {
+    let data_array: &[String] = expect_fields(args, 2)?;
+
+    let channel: ChannelName = channel_name(&data_array[0])?;
+    let id: String = data_array[1].clone();
+    check_permission(&channel, &reg_id).await?;
+
+    let (target, consumer, delivered): (ChannelName, String, String) =
+        (channel.clone(), reg_id.clone(), id.clone());
+    with_storage(move |storage| storage.acknowledge(&target, &consumer, &delivered)).await?;
+    notify::wake(&channel);
+    append_log(PROG, &format!("Delivered {} to {}", id, reg_id));
+    Ok(ack_ok())
+}
+
+// Nack/channel_id hands the message back to be handed out again straight away,
+// Nack/channel_id_delayms after a delay and Nack/channel_id_delayms_reason also
+// logs why
+async fn nack_msg(args: &[String], reg_id: String) -> ServerResult<Responses> {
+    let (channel, id, delay, reason): (ChannelName, String, Duration, Option<&str>) =
+        match args {
+            [channel, id] => (channel_name(channel)?, id.clone(), Duration::ZERO, None),
+            [channel, id, delay] => {
+                (channel_name(channel)?, id.clone(), requeue_delay(delay)?, None)
             }
-            Err(e) => {
-                append_log(PROG, &format!("Storing message failed with: {}", e));
-                no_handel(tcp_stream);
+            [channel, id, delay, reason] => (
+                channel_name(channel)?,
+                id.clone(),
+                requeue_delay(delay)?,
+                Some(nack_reason(reason)?),
+            ),
+            args => {
+                return Err(ServerError::Malformed(format!(
+                    "expected 2 to 4 fields in the payload, got {}",
+                    args.len()
+                )))
             }
-        },
-        false => {
+        };
+    check_permission(&channel, &reg_id).await?;
+
+    let (target, consumer, rejected): (ChannelName, String, String) =
+        (channel.clone(), reg_id.clone(), id.clone());
+    let buried: Option<ChannelName> =
+        with_storage(move |storage| storage.reject(&target, &consumer, &rejected, delay)).await?;
+
+    let reason: &str = reason.unwrap_or("no reason given");
+    match buried {
+        Some(dead_letter) => {
+            notify::wake(&dead_letter);
             append_log(
                 PROG,
                 &format!(
-                    "Permission denied while acessing channel {}, by client {}",
-                    channel, reg_id
+                    "{} gave up on {} in {} ({}), it ran out of attempts and went to {}",
+                    reg_id, id, channel, reason, dead_letter
                 ),
             );
-            no_permission(tcp_stream);
+        }
+        None => {
+            // The message is back once the delay is over
+            match delay.is_zero() {
+                true => notify::wake(&channel),
+                false => notify::wake_after(&channel, delay),
+            }
+            append_log(
+                PROG,
+                &format!("{} handed back {} in {} ({})", reg_id, id, channel, reason),
+            );
         }
     }
+    Ok(ack_ok())
 }
 
-fn check_msg(channel: &str, reg: &str, tcp_stream: &TcpStream) {
-    let mut conn: PooledConn = create_conn();
+// A day, longer than that the message should go to the dead-letter channel instead
+const MAX_REQUEUE_DELAY: u64 = 24 * 60 * 60 * 1000;
 
-    match check_permission(&channel, &reg) {
-        true => {
-            // read the latest message in the database
-            let check_query: &str = &format!(
-                r"SELECT uuid, message_type, message FROM Artisan_Messenger.{} WHERE processed = '0' LIMIT 1",
-                channel
-            );
+fn requeue_delay(delay: &str) -> ServerResult<Duration> {
+    match delay.parse::<u64>() {
+        Ok(delay) if delay <= MAX_REQUEUE_DELAY => Ok(Duration::from_millis(delay)),
+        _ => Err(ServerError::Malformed(format!(
+            "delay must be at most {} milliseconds, got {:?}",
+            MAX_REQUEUE_DELAY, delay
+        ))),
+    }
+}
 
-            // Reding the data from db
-            let packed_messages =
-                conn.query_map(check_query, |(uuid, message_type, message)| Message {
-                    uuid,
-                    message_type,
-                    message,
-                });
-
-            // repacking into an array of messages
-            let messages: Option<Vec<Message>> = match packed_messages {
-                Ok(message_array) => Some(message_array),
-                Err(_) => None,
-            };
-
-            match messages {
-                Some(mes) => {
-                    for message in mes {
-                        let message_data: String = message.message;
-
-                        let message_integrity: String = message.uuid;
-                        let new_integrity: String = create_hash(&message_data);
-
-                        let hash_check: bool = new_integrity == message_integrity;
-                        match hash_check {
-                            true => {
-                                send_ack_ds(message_data, tcp_stream);
-                                // Marking message deliveredI
-                            }
-                            false => sec_fault(tcp_stream),
-                        }
-                    }
-                }
-                None => send_ack_ok(tcp_stream), // no data
-            }
+// Reasons only end up in the log, one line each
+fn nack_reason(reason: &str) -> ServerResult<&str> {
+    match reason.len() <= 256 && !reason.chars().any(char::is_control) {
+        true => Ok(reason),
+        false => Err(ServerError::Malformed(String::from(
+            "reason must be at most 256 characters on one line",
+        ))),
+    }
+}
+
+// DeadLetter/channel_deadletter_maxattempts sends messages handed out maxattempts
+// times without an ack to deadletter, DeadLetter/channel stops that. The client has
+// to be registered on both channels.
+async fn dead_letter(args: &[String], reg_id: String) -> ServerResult<Responses> {
+    let (channel, dead_letter): (ChannelName, Option<(ChannelName, u32)>) = match args {
+        [channel] => (channel_name(channel)?, None),
+        [channel, target, max_attempts] => {
+            let target: ChannelName = channel_name(target)?;
+            (channel_name(channel)?, Some((target, attempts_limit(max_attempts)?)))
+        }
+        args => {
+            return Err(ServerError::Malformed(format!(
+                "expected 1 or 3 fields in the payload, got {}",
+                args.len()
+            )))
+        }
+    };
+    check_permission(&channel, &reg_id).await?;
+    if let Some((target, _)) = &dead_letter {
+        if target.as_str() == channel.as_str() {
+            return Err(ServerError::Malformed(format!(
+                "{} can't be its own dead-letter channel",
+                channel
+            )));
         }
-        false => no_permission(tcp_stream),
+        check_permission(target, &reg_id).await?;
     }
+
+    let (name, setting) = (channel.clone(), dead_letter.clone());
+    with_storage(move |storage| {
+        storage.set_dead_letter(&name, setting.as_ref().map(|(target, max)| (target, *max)))
+    })
+    .await?;
+    match dead_letter {
+        Some((target, max_attempts)) => append_log(
+            PROG,
+            &format!(
+                "{} sends messages to {} after {} attempts",
+                channel, target, max_attempts
+            ),
+        ),
+        None => append_log(PROG, &format!("{} has no dead-letter channel", channel)),
+    }
+    Ok(ack_ok())
 }
 
-fn ack_msg(data: &str, _: String, tcp_stream: &TcpStream) {
-    // No perm check because we mark done based on the message hex
-    let mut conn: PooledConn = create_conn();
-    let data_array: Vec<String> = data.split('_').map(|s| s.to_string()).collect();
+fn attempts_limit(max_attempts: &str) -> ServerResult<u32> {
+    match max_attempts.parse::<u32>() {
+        Ok(max_attempts) if max_attempts > 0 => Ok(max_attempts),
+        _ => Err(ServerError::Malformed(format!(
+            "max attempts must be a number above 0, got {:?}",
+            max_attempts
+        ))),
+    }
+}
 
-    let channel: String = String::from(data_array[0].clone());
-    let message: String = String::from(data_array[1].clone());
+// Replay/channel moves the messages the channel buried in its dead-letter channel
+// back into it. The client needs permission on both channels.
+async fn replay(args: &[String], reg_id: String) -> ServerResult<Responses> {
+    let channel: ChannelName = channel_name(&expect_fields(args, 1)?[0])?;
+    check_permission(&channel, &reg_id).await?;
 
-    let delivered: String = format!(
-        r"UPDATE Artisan_Messenger.{} SET processed = '1' WHERE message = '{}'",
-        channel, message
-    );
+    let target: ChannelName = channel.clone();
+    let dead_letter: ChannelName =
+        match with_storage(move |storage| storage.dead_letter(&target)).await? {
+            Some((dead_letter, _)) => dead_letter,
+            None => {
+                return Err(ServerError::Malformed(format!(
+                    "{} has no dead-letter channel",
+                    channel
+                )))
+            }
+        };
+    check_permission(&dead_letter, &reg_id).await?;
 
-    match conn.query_drop(delivered) {
-        Ok(_) => {
-            append_log(PROG, &format!("Delivered {}", message));
-            send_ack_ok(tcp_stream);
-        }
-        Err(e) => {
-            append_log(
-                PROG,
-                &format!("Couldn't mark {} as delivered got: {}", message, e),
-            );
-            no_handel(tcp_stream);
-        }
-    };
+    let target: ChannelName = channel.clone();
+    let replayed: usize =
+        with_storage(move |storage| storage.replay(&target, &dead_letter)).await?;
+    notify::wake(&channel);
+    append_log(
+        PROG,
+        &format!("{} replayed {} dead letters into {}", reg_id, replayed, channel),
+    );
+    Ok(ack_ok())
 }
@@ -0,0 +1,445 @@
+use {
+    serde::Deserialize,
+    std::{collections::HashMap, env, fs, net::SocketAddr, sync::OnceLock},
+};
+
+// Loaded once at startup, everything else reads it through get()
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listener: ListenerConfig,
+    pub database: DatabaseConfig,
+    pub storage: StorageConfig,
+    pub auth: AuthConfig,
+    pub tls: TlsConfig,
+    pub http: HttpConfig,
+    pub websocket: WebsocketConfig,
+    pub unix: UnixConfig,
+    pub limits: LimitsConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub port: u16,
+    pub schema: String,
+    pub pool_min: usize,
+    pub pool_max: usize,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: Backend,
+    pub path: String, // sqlite only
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Mysql,
+    Sqlite,
+    Memory,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub keys_file: String,
+    // Accept the old unkeyed hash from clients that have no key
+    pub allow_unkeyed: bool,
+    pub max_clock_skew: u64, // seconds
+    pub nonce_cache_size: usize,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    pub cert: String, // pem certificate chain
+    pub key: String,  // pem private key
+    // Setting a ca turns on mutual tls, the client certificate's common name
+    // has to match the registration id the client sends
+    pub client_ca: String,
+    pub require_client_cert: bool,
+    pub handshake_timeout: u64, // seconds
+}
+
+// Optional HTTP gateway onto the same commands, plain http only, put a tls
+// terminating proxy in front of it
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub enabled: bool,
+    pub address: String,
+}
+
+// Optional WebSocket listener for browsers, plain ws only like the http gateway
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebsocketConfig {
+    pub enabled: bool,
+    pub address: String,
+    pub handshake_timeout: u64, // seconds
+}
+
+// Optional unix socket for clients on the same host, access is decided by the
+// socket file's permissions
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct UnixConfig {
+    pub enabled: bool,
+    pub path: String,
+    pub mode: String, // octal, like chmod
+    // Peer uid to the registration id that uid may send as, works like the
+    // common name of a client certificate
+    pub uids: HashMap<u32, String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_frame_size: usize, // bytes
+    pub shutdown_timeout: u64, // seconds
+    // Most unacknowledged messages a subscriber may ask to have pushed at once
+    pub max_in_flight: usize,
+    // Most tagged requests one connection may have running at once, also how many
+    // responses may wait for its writer
+    pub max_pipelined: usize,
+    // Longest a Check may wait for a message, milliseconds
+    pub max_check_wait: u64,
+    // How long a handed out message stays hidden waiting for its ack before it's
+    // handed out again, milliseconds
+    pub visibility_timeout: u64,
+    // How long a reader's ack is remembered once the message is gone, so acking it
+    // again is still answered as already acked, milliseconds
+    pub ack_retention: u64,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        ListenerConfig {
+            address: String::from("0.0.0.0:9518"),
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            port: 3306,
+            schema: String::from("Artisan_Messenger"),
+            pool_min: 4,
+            pool_max: 8,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: Backend::Mysql,
+            path: String::from("ironpulse.db"),
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            keys_file: String::new(),
+            allow_unkeyed: false,
+            max_clock_skew: 300,
+            nonce_cache_size: 100_000,
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            enabled: false,
+            cert: String::new(),
+            key: String::new(),
+            client_ca: String::new(),
+            require_client_cert: false,
+            handshake_timeout: 10,
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            enabled: false,
+            address: String::from("127.0.0.1:9519"),
+        }
+    }
+}
+
+impl Default for WebsocketConfig {
+    fn default() -> Self {
+        WebsocketConfig {
+            enabled: false,
+            address: String::from("127.0.0.1:9520"),
+            handshake_timeout: 10,
+        }
+    }
+}
+
+impl Default for UnixConfig {
+    fn default() -> Self {
+        UnixConfig {
+            enabled: false,
+            path: String::from("/run/ironpulse/ironpulse.sock"),
+            mode: String::from("0660"),
+            uids: HashMap::new(),
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_frame_size: 1024 * 1024,
+            shutdown_timeout: 30,
+            max_in_flight: 64,
+            max_pipelined: 64,
+            max_check_wait: 30_000,
+            visibility_timeout: 30_000,
+            ack_retention: 3_600_000,
+        }
+    }
+}
+
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("Configuration used before it was loaded")
+}
+
+// Reads the file (if given), applies the environment overrides and validates the
+// result. Every problem found is reported, not just the first one.
+pub fn load(path: Option<&str>) -> Result<&'static Config, String> {
+    let mut config: Config = match path {
+        Some(path) => {
+            let contents: String = fs::read_to_string(path)
+                .map_err(|e| format!("Couldn't read config file {}: {}", path, e))?;
+            serde_yaml::from_str(&contents)
+                .map_err(|e| format!("Couldn't parse config file {}: {}", path, e))?
+        }
+        None => Config::default(),
+    };
+
+    let mut errors: Vec<String> = Vec::new();
+    apply_env(&mut config, &mut errors);
+    validate(&config, &mut errors);
+
+    match errors.is_empty() {
+        true => Ok(CONFIG.get_or_init(|| config)),
+        false => Err(errors.join("\n")),
+    }
+}
+
+fn apply_env(config: &mut Config, errors: &mut Vec<String>) {
+    if let Ok(value) = env::var("IRONPULSE_LISTEN_ADDRESS") {
+        config.listener.address = value;
+    }
+    if let Ok(value) = env::var("IRONPULSE_DB_SCHEMA") {
+        config.database.schema = value;
+    }
+    if let Ok(value) = env::var("IRONPULSE_STORAGE_BACKEND") {
+        match serde_yaml::from_str::<Backend>(&value) {
+            Ok(backend) => config.storage.backend = backend,
+            Err(_) => errors.push(format!(
+                "IRONPULSE_STORAGE_BACKEND must be mysql, sqlite or memory, got {:?}",
+                value
+            )),
+        }
+    }
+    if let Ok(value) = env::var("IRONPULSE_STORAGE_PATH") {
+        config.storage.path = value;
+    }
+    if let Ok(value) = env::var("IRONPULSE_KEYS_FILE") {
+        config.auth.keys_file = value;
+    }
+    override_number("IRONPULSE_ALLOW_UNKEYED", &mut config.auth.allow_unkeyed, errors);
+    override_number("IRONPULSE_TLS_ENABLED", &mut config.tls.enabled, errors);
+    if let Ok(value) = env::var("IRONPULSE_TLS_CERT") {
+        config.tls.cert = value;
+    }
+    if let Ok(value) = env::var("IRONPULSE_TLS_KEY") {
+        config.tls.key = value;
+    }
+    if let Ok(value) = env::var("IRONPULSE_TLS_CLIENT_CA") {
+        config.tls.client_ca = value;
+    }
+    override_number(
+        "IRONPULSE_TLS_REQUIRE_CLIENT_CERT",
+        &mut config.tls.require_client_cert,
+        errors,
+    );
+    override_number("IRONPULSE_TLS_HANDSHAKE_TIMEOUT", &mut config.tls.handshake_timeout, errors);
+    override_number("IRONPULSE_HTTP_ENABLED", &mut config.http.enabled, errors);
+    if let Ok(value) = env::var("IRONPULSE_HTTP_ADDRESS") {
+        config.http.address = value;
+    }
+    override_number("IRONPULSE_WS_ENABLED", &mut config.websocket.enabled, errors);
+    if let Ok(value) = env::var("IRONPULSE_WS_ADDRESS") {
+        config.websocket.address = value;
+    }
+    override_number(
+        "IRONPULSE_WS_HANDSHAKE_TIMEOUT",
+        &mut config.websocket.handshake_timeout,
+        errors,
+    );
+    override_number("IRONPULSE_UNIX_ENABLED", &mut config.unix.enabled, errors);
+    if let Ok(value) = env::var("IRONPULSE_UNIX_PATH") {
+        config.unix.path = value;
+    }
+    if let Ok(value) = env::var("IRONPULSE_UNIX_MODE") {
+        config.unix.mode = value;
+    }
+    override_number("IRONPULSE_MAX_CLOCK_SKEW", &mut config.auth.max_clock_skew, errors);
+    override_number("IRONPULSE_NONCE_CACHE_SIZE", &mut config.auth.nonce_cache_size, errors);
+    override_number("IRONPULSE_DB_PORT", &mut config.database.port, errors);
+    override_number("IRONPULSE_DB_POOL_MIN", &mut config.database.pool_min, errors);
+    override_number("IRONPULSE_DB_POOL_MAX", &mut config.database.pool_max, errors);
+    override_number("IRONPULSE_MAX_FRAME_SIZE", &mut config.limits.max_frame_size, errors);
+    override_number("IRONPULSE_SHUTDOWN_TIMEOUT", &mut config.limits.shutdown_timeout, errors);
+    override_number("IRONPULSE_MAX_IN_FLIGHT", &mut config.limits.max_in_flight, errors);
+    override_number("IRONPULSE_MAX_PIPELINED", &mut config.limits.max_pipelined, errors);
+    override_number("IRONPULSE_MAX_CHECK_WAIT", &mut config.limits.max_check_wait, errors);
+    override_number("IRONPULSE_VISIBILITY_TIMEOUT", &mut config.limits.visibility_timeout, errors);
+    override_number("IRONPULSE_ACK_RETENTION", &mut config.limits.ack_retention, errors);
+}
+
+// Numbers and true/false flags
+fn override_number<T: std::str::FromStr>(name: &str, field: &mut T, errors: &mut Vec<String>) {
+    if let Ok(value) = env::var(name) {
+        match value.parse::<T>() {
+            Ok(number) => *field = number,
+            Err(_) => errors.push(format!("{} has an invalid value {:?}", name, value)),
+        }
+    }
+}
+
+fn validate(config: &Config, errors: &mut Vec<String>) {
+    if config.listener.address.parse::<SocketAddr>().is_err() {
+        errors.push(format!(
+            "listener.address must be an ip:port pair, got {:?}",
+            config.listener.address
+        ));
+    }
+
+    if config.database.port == 0 {
+        errors.push(String::from("database.port can't be 0"));
+    }
+
+    // The schema is put straight into queries, so only plain identifiers are allowed
+    let schema: &str = &config.database.schema;
+    if schema.is_empty()
+        || schema.len() > 64
+        || !schema.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        errors.push(format!(
+            "database.schema must be 1 to 64 letters, digits or underscores, got {:?}",
+            schema
+        ));
+    }
+
+    if config.database.pool_max == 0 {
+        errors.push(String::from("database.pool_max must be at least 1"));
+    }
+    if config.database.pool_min > config.database.pool_max {
+        errors.push(format!(
+            "database.pool_min ({}) can't be larger than database.pool_max ({})",
+            config.database.pool_min, config.database.pool_max
+        ));
+    }
+
+    if config.storage.backend == Backend::Sqlite && config.storage.path.is_empty() {
+        errors.push(String::from("storage.path is required for the sqlite backend"));
+    }
+
+    // There is no default keys file, so this is the one setting a server started
+    // without a config still has to be given
+    if config.auth.keys_file.is_empty() && !config.auth.allow_unkeyed {
+        errors.push(String::from(
+            "auth.keys_file is required unless auth.allow_unkeyed is true, \
+             set it in the config file or with IRONPULSE_KEYS_FILE",
+        ));
+    }
+
+    if config.auth.nonce_cache_size == 0 {
+        errors.push(String::from("auth.nonce_cache_size must be at least 1"));
+    }
+
+    if config.tls.enabled && (config.tls.cert.is_empty() || config.tls.key.is_empty()) {
+        errors.push(String::from("tls.cert and tls.key are required when tls is enabled"));
+    }
+    if config.tls.require_client_cert && config.tls.client_ca.is_empty() {
+        errors.push(String::from("tls.require_client_cert needs tls.client_ca"));
+    }
+    if config.tls.enabled && config.tls.handshake_timeout == 0 {
+        errors.push(String::from("tls.handshake_timeout must be at least 1"));
+    }
+
+    if config.http.enabled && config.http.address.parse::<SocketAddr>().is_err() {
+        errors.push(format!(
+            "http.address must be an ip:port pair, got {:?}",
+            config.http.address
+        ));
+    }
+
+    if config.websocket.enabled && config.websocket.address.parse::<SocketAddr>().is_err() {
+        errors.push(format!(
+            "websocket.address must be an ip:port pair, got {:?}",
+            config.websocket.address
+        ));
+    }
+    if config.websocket.enabled && config.websocket.handshake_timeout == 0 {
+        errors.push(String::from("websocket.handshake_timeout must be at least 1"));
+    }
+
+    if config.unix.enabled && config.unix.path.is_empty() {
+        errors.push(String::from("unix.path is required when the unix socket is enabled"));
+    }
+    if config.unix.enabled && socket_mode(&config.unix.mode).is_none() {
+        errors.push(format!(
+            "unix.mode must be octal permissions like 0660, got {:?}",
+            config.unix.mode
+        ));
+    }
+
+    if config.limits.max_frame_size == 0 || config.limits.max_frame_size > u32::MAX as usize {
+        errors.push(format!(
+            "limits.max_frame_size must be between 1 and {}, got {}",
+            u32::MAX,
+            config.limits.max_frame_size
+        ));
+    }
+    if config.limits.max_in_flight == 0 {
+        errors.push(String::from("limits.max_in_flight must be at least 1"));
+    }
+    if config.limits.max_pipelined == 0 {
+        errors.push(String::from("limits.max_pipelined must be at least 1"));
+    }
+    if config.limits.visibility_timeout == 0 {
+        errors.push(String::from("limits.visibility_timeout must be at least 1"));
+    }
+}
+
+// "0660" or "660" to the permission bits
+pub fn socket_mode(mode: &str) -> Option<u32> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(bits) if bits <= 0o777 => Some(bits),
+        _ => None,
+    }
+}
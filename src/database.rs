@@ -1,66 +1,57 @@
-use core::panic;
-
 use logging::append_log;
 use mysql::*;
-use pretty::halt;
 use recs::retrive;
 use std::fs;
 use system::del_file;
 
-use crate::{skel::Database, PROG};
+use crate::{config, skel::Database, PROG};
 
-pub fn create_pool() -> Pool {
-    let (db_username, db_password, db_host, database) = match read_credentials() {
-        Some(db) => (db.username, db.password, db.hostaddr, db.database),
-        None => {
-            halt("No database credentials found, use rescs and store database credentials as Ironpulse, database, /tmp/database.dk");
-            panic!()
-        }
-    };
+pub fn create_pool() -> anyhow::Result<Pool> {
+    let db: Database = read_credentials()?;
 
+    let settings = &config::get().database;
     let url: String = format!(
-        "mysql://{}:{}@{}:3306/{}",
-        db_username, db_password, db_host, database
+        "mysql://{}:{}@{}:{}/{}",
+        db.username, db.password, db.hostaddr, settings.port, db.database
     );
-    let pool: Pool =
-        Pool::new_manual(4, 8, url).expect("Failed to create the database connection pool");
-    pool
-}
-
-pub fn create_conn() -> PooledConn {
-    return create_pool().get_conn().unwrap();
+    let pool: Pool = Pool::new_manual(settings.pool_min, settings.pool_max, url)
+        .map_err(|e| anyhow::anyhow!("Failed to create the database connection pool: {}", e))?;
+    Ok(pool)
 }
 
-// ! make a struct for the database creds
-fn read_credentials() -> Option<Database> {
+fn read_credentials() -> anyhow::Result<Database> {
     let owner: String = String::from("ironpulse");
     let name: String = String::from("database");
     match retrive(owner, name) {
-        Some(bol) => match bol {
-            true => {
-                // retrive the data
-                let database_creds: String = match fs::read_to_string("/tmp/database.dk") {
-                    Ok(data) => data,
-                    Err(e) => {
-                        append_log(PROG, &format!("Error reading database credentials: {}", e));
-                        panic!();
-                    }
-                };
-                del_file("/tmp/database.dk");
-                // unpack and map
-                let data: Vec<String> = database_creds.split('/').map(|s| s.to_string()).collect();
-                return Some(Database {
-                    username: data[0].to_owned(),
-                    password: data[1].to_owned(),
-                    hostaddr: data[2].to_owned(),
-                    database: data[3].to_owned(),
-                });
-            }
-            false => {
-                append_log(PROG, "Could not read database credentials from recs");
-                panic!("Check logs");
+        Some(true) => {
+            // retrive the data
+            let database_creds: String = match fs::read_to_string("/tmp/database.dk") {
+                Ok(data) => data,
+                Err(e) => {
+                    append_log(PROG, &format!("Error reading database credentials: {}", e));
+                    anyhow::bail!("Error reading database credentials: {}", e);
+                }
+            };
+            del_file("/tmp/database.dk");
+
+            // unpack and map
+            let data: Vec<&str> = database_creds.trim().split('/').collect();
+            match data.as_slice() {
+                [username, password, hostaddr, database] => Ok(Database {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                    hostaddr: hostaddr.to_string(),
+                    database: database.to_string(),
+                }),
+                _ => anyhow::bail!(
+                    "Database credentials should be username/password/host/database"
+                ),
             }
-        },
-        None => panic!("Unable to communicate with recs"),
+        }
+        Some(false) => {
+            append_log(PROG, "Could not read database credentials from recs");
+            anyhow::bail!("No database credentials found, use recs and store database credentials as ironpulse, database, /tmp/database.dk")
+        }
+        None => anyhow::bail!("Unable to communicate with recs"),
     }
 }
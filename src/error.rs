@@ -0,0 +1,96 @@
+use {crate::skel::StatCode, std::fmt};
+
+// Everything that can go wrong while handling a request. Handlers return these
+// and the connection turns them into a status code and a log entry in one place,
+// so nothing a client sends can take a handler down.
+#[derive(Debug)]
+pub enum ServerError {
+    Malformed(String),        // request or payload couldn't be parsed
+    UnknownCommand(String),   // command we don't handle
+    Integrity(String),        // hash, hmac or replay check failed
+    Permission(String),       // client isn't registered on the channel
+    InvalidChannel(String),   // channel name failed validation
+    ChannelNotFound(String),  // channel doesn't exist in storage
+    ChannelExists(String),    // channel was already created
+    UnknownMessage(String),   // no such message waiting on the channel
+    AlreadyAcked(String),     // the client is already done with the message
+    TooLarge(String),         // frame or stored field over its limit
+    RateLimited(String),      // a limit on how much clients send was hit, worth retrying
+    Unavailable(String),      // backend couldn't be reached, worth retrying
+    Storage(anyhow::Error),   // backend failed in some other way
+}
+
+pub type ServerResult<T> = Result<T, ServerError>;
+
+impl ServerError {
+    pub fn status(&self) -> StatCode {
+        match self {
+            ServerError::Malformed(_) => StatCode::MalRq,
+            ServerError::UnknownCommand(_) => StatCode::UnCmd,
+            ServerError::Integrity(_) => StatCode::SecFt,
+            ServerError::Permission(_) => StatCode::NoPer,
+            ServerError::InvalidChannel(_) => StatCode::NoPer,
+            ServerError::ChannelNotFound(_) => StatCode::NoChn,
+            ServerError::ChannelExists(_) => StatCode::ChExs,
+            ServerError::UnknownMessage(_) => StatCode::NoMsg,
+            ServerError::AlreadyAcked(_) => StatCode::Acked,
+            ServerError::TooLarge(_) => StatCode::TooLg,
+            ServerError::RateLimited(_) => StatCode::RtLmt,
+            ServerError::Unavailable(_) => StatCode::NoBkd,
+            ServerError::Storage(_) => StatCode::NoHnd,
+        }
+    }
+
+    // Sent next to the status code so clients can branch on it. The details stay
+    // in the log, security faults and unexpected failures get no reason at all.
+    pub fn reason(&self) -> Option<&'static str> {
+        match self {
+            ServerError::Malformed(_) => Some("malformed"),
+            ServerError::UnknownCommand(_) => Some("unknown_command"),
+            ServerError::Integrity(_) => None,
+            ServerError::Permission(_) => Some("not_registered"),
+            ServerError::InvalidChannel(_) => Some("invalid_channel_name"),
+            ServerError::ChannelNotFound(_) => Some("channel_not_found"),
+            ServerError::ChannelExists(_) => Some("channel_exists"),
+            ServerError::UnknownMessage(_) => Some("message_not_found"),
+            ServerError::AlreadyAcked(_) => Some("already_acked"),
+            ServerError::TooLarge(_) => Some("too_large"),
+            ServerError::RateLimited(_) => Some("rate_limited"),
+            ServerError::Unavailable(_) => Some("backend_unavailable"),
+            ServerError::Storage(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::Malformed(reason) => write!(f, "Malformed request: {}", reason),
+            ServerError::UnknownCommand(command) => write!(f, "Unknown command: {}", command),
+            ServerError::Integrity(reason) => write!(f, "Integrity check failed: {}", reason),
+            ServerError::Permission(reason) => write!(f, "Permission denied: {}", reason),
+            ServerError::InvalidChannel(name) => write!(f, "Invalid channel name: {:?}", name),
+            ServerError::ChannelNotFound(name) => write!(f, "Channel {} doesn't exist", name),
+            ServerError::ChannelExists(name) => write!(f, "Channel {} already exists", name),
+            ServerError::UnknownMessage(id) => write!(f, "No message {} waiting", id),
+            ServerError::AlreadyAcked(id) => write!(f, "Message {} was already acked", id),
+            ServerError::TooLarge(reason) => write!(f, "Too large: {}", reason),
+            ServerError::RateLimited(reason) => write!(f, "Rate limited: {}", reason),
+            ServerError::Unavailable(reason) => write!(f, "Backend unavailable: {}", reason),
+            ServerError::Storage(e) => write!(f, "Storage failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+// Backends raise the specific variants through anyhow, anything else is a plain
+// storage failure
+impl From<anyhow::Error> for ServerError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<ServerError>() {
+            Ok(error) => error,
+            Err(e) => ServerError::Storage(e),
+        }
+    }
+}
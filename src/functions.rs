@@ -1,138 +1,179 @@
-use std::{net::TcpStream, io::Write};
+use std::io;
 use logging::append_log;
-use mysql::prelude::Queryable;
 use system::create_hash;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-use crate::{database::create_conn, PROG, skel::{Responses, StatCode, Payload, Integrity}};
-
-pub fn create_message_table(table_name: &str) -> bool {
-    let mut conn = create_conn();
-    match conn.query_drop(format!(
-        r"CREATE TABLE Artisan_Messenger.{} (
-            uuid VARCHAR(380) NOT NULL,
-            message_type VARCHAR(1024) NOT NULL,
-            message VARCHAR(4096) NOT NULL,
-            processed BOOLEAN not null DEFAULT 0, 
-            PRIMARY KEY (uuid)
-        )",
-        table_name
-    )) {
-        Ok(_) => {
-            append_log(PROG, &format!("Table created for {}", table_name));
-            true
-        }
-        Err(e) => {
-            append_log(
-                PROG,
-                &format!("Creating table {}, FAILED: {}", table_name, e),
-            );
-            false
-        }
+use crate::{
+    binary,
+    config,
+    error::{ServerError, ServerResult},
+    json,
+    storage::with_storage,
+    PROG,
+    skel::{ChannelName, Client, Encoding, Responses, StatCode, Payload, Integrity},
+};
+
+// Rejects anything that isn't a valid channel name before it gets near storage
+pub fn channel_name(name: &str) -> ServerResult<ChannelName> {
+    match ChannelName::parse(name) {
+        Some(channel) => Ok(channel),
+        None => Err(ServerError::InvalidChannel(name.to_string())),
     }
 }
 
-pub fn create_permission_table(table_name: &str) -> bool {
-    let mut conn = create_conn();
-    match conn.query_drop(format!(
-        r"CREATE TABLE Artisan_Messenger.{}_permission (
-            uuid VARCHAR(380) NOT NULL,
-            PRIMARY KEY (uuid)
-        )",
-        table_name
-    )) {
-        Ok(_) => {
-            append_log(PROG, &format!("Table created for {}", table_name));
-            true
-        }
-        Err(e) => {
-            append_log(
-                PROG,
-                &format!("Creating table {}_permission, FAILED: {}", table_name, e),
-            );
-            false
-        }
+// Errors unless the client is registered on the channel
+pub async fn check_permission(table: &ChannelName, uuid: &str) -> ServerResult<()> {
+    let (channel, client) = (table.clone(), uuid.to_string());
+    match with_storage(move |storage| storage.check_permission(&channel, &client)).await? {
+        true => Ok(()),
+        false => Err(ServerError::Permission(format!(
+            "client {} on channel {}",
+            uuid, table
+        ))),
     }
 }
 
-pub fn check_permission(table: &str, uuid: &str) -> bool {
-    let mut conn = create_conn();
-    let perm_query: String = format!(
-        "SELECT COUNT(*) FROM {}_permission WHERE uuid = '{}'",
-        table, uuid
-    );
-    let count: i32 = match conn.query_first(perm_query) {
-        Ok(Some(result)) => result,
-        Ok(None) => 0,
-        Err(e) => {
-            append_log(PROG, &format!("DATABASE UNAVAILABLE: {}", e));
-            0
-        }
-    };
-    
-    // This is where the delivered messages get deleted
-    let maintence_query: String = format!("DELETE FROM {} WHERE processed = '1'", table);
-    let _ = match conn.query_drop(maintence_query) {
-        Ok(_) => append_log(PROG, "Maintence drops"),
-        Err(e) => append_log(PROG, &format!("Maintence Drops Failed: {}", e)),
-    };
-
-    match count {
-        0 => false,
-        _ => true,
+// Commands take a fixed number of payload fields
+pub fn expect_fields(args: &[String], count: usize) -> ServerResult<&[String]> {
+    match args.len() == count {
+        true => Ok(args),
+        false => Err(ServerError::Malformed(format!(
+            "expected {} fields in the payload, got {}",
+            count,
+            args.len()
+        ))),
     }
 }
 
-pub fn payload_integrity(payload: &str) -> bool {
-    let data: Vec<String> = payload.split('_').map(|s| s.to_string()).collect();
-
-    let body_data: &str = &data[2];
-    let body_hash: &str = &data[3];
-    let new_hash: &str = &create_hash(&body_data.to_string());
-
-    let check: bool = body_hash == new_hash;
-    match check {
-        true => true,
-        false => false,
+// Store payloads are channel_type_body_hash, the hash has to match the body
+pub fn payload_integrity(data: &[String]) -> bool {
+    match (data.get(2), data.get(3)) {
+        (Some(body_data), Some(body_hash)) => body_hash == &create_hash(body_data),
+        _ => false,
     }
 }
 
-// ? WRITTING FUNCTIONS
-pub fn sec_fault(tcp_stream: &TcpStream) {
-    let sec_fault: Responses = Responses::Code(StatCode::SecFt);
-    stream_write(sec_fault, tcp_stream);
+// Text and JSON frames have to be UTF-8
+pub fn frame_text(frame: Vec<u8>) -> ServerResult<String> {
+    String::from_utf8(frame).map_err(|e| ServerError::Malformed(format!("Frame isn't UTF-8: {}", e)))
 }
 
-pub fn send_ack_ds(data: String, tcp_stream: &TcpStream) {
-    let ack: Responses = Responses::Data(
+// ? RESPONSE FUNCTIONS
+pub fn ack_ds(data: String) -> Responses {
+    Responses::Data(
         StatCode::AckDs,
         Payload::Data(data.clone(), Integrity::Hash(create_hash(&data))),
-    );
-    stream_write(ack, tcp_stream);
+    )
 }
 
-pub fn send_ack_dr(tcp_stream: &TcpStream) {
-    let ack: Responses = Responses::Code(StatCode::AckDr);
-    stream_write(ack, tcp_stream);
+// A stored message and the id it's acked by, the hash covers the hex form whatever
+// the connection gets
+pub fn ack_message(data: String, id: String) -> Responses {
+    Responses::Data(
+        StatCode::AckDs,
+        Payload::Hex(data.clone(), Integrity::Hash(create_hash(&data)), id),
+    )
 }
 
-pub fn send_ack_ok(tcp_stream: &TcpStream) {
-    let ack: Responses = Responses::Code(StatCode::AckOk);
-    stream_write(ack, tcp_stream);
+pub fn ack_dr() -> Responses {
+    Responses::Code(StatCode::AckDr)
+}
+
+pub fn ack_ok() -> Responses {
+    Responses::Code(StatCode::AckOk)
+}
+
+// Every handler result goes through here, errors become their status code and a
+// log entry
+pub async fn respond(result: ServerResult<Responses>, client: &Client) {
+    let response: Responses = match result {
+        Ok(response) => response,
+        Err(e) => error_response(e, wants_reasons(client)),
+    };
+    stream_write(response, client).await;
+}
+
+// Sends a subscribed channel's message, or the error that ended the subscription,
+// without a request to answer. It carries the channel instead of a tag.
+pub async fn push(channel: &ChannelName, result: ServerResult<Responses>, client: &Client) {
+    let response: Responses = match result {
+        Ok(response) => response,
+        Err(e) => error_response(e, wants_reasons(client)),
+    };
+
+    let pushed: Vec<u8> = match client.encoding {
+        Encoding::Binary => binary::render_push(channel, &response),
+        Encoding::Json => json::render_event(channel, &response).into_bytes(),
+        Encoding::Text => format!("{},channel={}", response, channel).into_bytes(),
+    };
+    if client.sender.send(pushed).await.is_err() {
+        append_log(PROG, "Client disconnected before the message was pushed");
+    }
 }
 
-pub fn no_handel(tcp_stream: &TcpStream) {
-    let no_handel: Responses = Responses::Code(StatCode::NoHnd);
-    stream_write(no_handel, tcp_stream);
+// Version 0 text clients only know bare codes
+fn wants_reasons(client: &Client) -> bool {
+    client.encoding != Encoding::Text || client.session.supports("reasons")
 }
 
-pub fn no_permission(tcp_stream: &TcpStream) {
-    let no_permission: Responses = Responses::Code(StatCode::NoPer);
-    stream_write(no_permission, tcp_stream);
+pub fn error_response(e: ServerError, reasons: bool) -> Responses {
+    append_log(PROG, &format!("{}", e));
+    match (e.reason(), reasons) {
+        (Some(reason), true) => Responses::Reason(e.status(), reason.to_string()),
+        _ => Responses::Code(e.status()),
+    }
 }
 
 // Not response functions
-pub fn stream_write(data: Responses, mut tcp_stream: &TcpStream) {
-    tcp_stream
-        .write(format!("{}", data).as_bytes())
-        .expect("Failed at writing onto the unix stream");
+pub async fn stream_write(data: Responses, client: &Client) {
+    // Echoing the tag so pipelined clients can match responses to requests
+    let response: Vec<u8> = match (client.encoding, &client.tag) {
+        (Encoding::Binary, tag) => binary::render(&data, tag.as_deref()),
+        (Encoding::Json, tag) => json::render(&data, tag.as_deref()).into_bytes(),
+        (Encoding::Text, Some(tag)) => format!("{},tag={}", data, tag).into_bytes(),
+        (Encoding::Text, None) => format!("{}", data).into_bytes(),
+    };
+
+    // The connection's writer task puts the frame on the wire, waiting for room
+    // when it's behind
+    if client.sender.send(response).await.is_err() {
+        append_log(PROG, "Client disconnected before the response was written");
+    }
+}
+
+// ? FRAMING FUNCTIONS
+// Every request and response is sent as a frame: a 4 byte big endian length
+// followed by that many bytes of payload. This lets one connection carry many
+// requests without the client having to half close the socket.
+pub async fn read_frame<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut length_bytes: [u8; 4] = [0; 4];
+    match stream.read_exact(&mut length_bytes).await {
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None), // client is done
+        Err(e) => return Err(e),
+    };
+
+    let length: usize = u32::from_be_bytes(length_bytes) as usize;
+    let max_frame_size: usize = config::get().limits.max_frame_size;
+    if length > max_frame_size {
+        // Carrying the ServerError so the connection can answer 413 before closing
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            ServerError::TooLarge(format!(
+                "Frame of {} bytes exceeds the {} byte limit",
+                length, max_frame_size
+            )),
+        ));
+    }
+
+    let mut frame: Vec<u8> = vec![0; length];
+    stream.read_exact(&mut frame).await?;
+    Ok(Some(frame))
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(data: &[u8], stream: &mut W) -> io::Result<()> {
+    let length: [u8; 4] = (data.len() as u32).to_be_bytes();
+    stream.write_all(&length).await?;
+    stream.write_all(data).await?;
+    stream.flush().await
 }
@@ -0,0 +1,254 @@
+use {
+    crate::{
+        auth,
+        commands::complex_processor,
+        config,
+        error::{ServerError, ServerResult},
+        functions::error_response,
+        json,
+        skel::{Responses, StatCode},
+        PROG,
+    },
+    hyper::{
+        body::HttpBody,
+        header::{HeaderMap, CONTENT_TYPE},
+        server::{conn::AddrIncoming, Builder},
+        service::{make_service_fn, service_fn},
+        Body, Method, Server, StatusCode,
+    },
+    logging::append_log,
+    serde::Deserialize,
+    std::{convert::Infallible, net::SocketAddr},
+    tokio::sync::watch,
+};
+
+// Body of POST /channels/{name}/messages, the same fields a Store request carries
+#[derive(Deserialize)]
+struct StoreBody {
+    #[serde(rename = "type")]
+    message_type: String,
+    message: String, // hex encoded
+    hash: String,
+}
+
+// Binds the gateway when it's turned on, so a bad address stops startup like the
+// main listener does
+pub fn bind() -> Result<Option<Builder<AddrIncoming>>, String> {
+    let settings = &config::get().http;
+    if !settings.enabled {
+        return Ok(None);
+    }
+
+    let address: SocketAddr = settings
+        .address
+        .parse()
+        .map_err(|e| format!("Bad http.address {}: {}", settings.address, e))?;
+    match Server::try_bind(&address) {
+        Ok(builder) => {
+            append_log(PROG, &format!("HTTP gateway listening on {}", address));
+            Ok(Some(builder))
+        }
+        Err(e) => Err(format!("Couldn't listen on {}: {}", address, e)),
+    }
+}
+
+// Serves until shutdown, then lets requests in flight finish
+pub async fn serve(builder: Builder<AddrIncoming>, mut shutdown: watch::Receiver<bool>) {
+    let service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+    let server = builder.serve(service).with_graceful_shutdown(async move {
+        let _ = shutdown.changed().await;
+    });
+
+    if let Err(e) = server.await {
+        append_log(PROG, &format!("HTTP gateway failed: {}", e));
+    }
+}
+
+async fn handle(request: hyper::Request<Body>) -> Result<hyper::Response<Body>, Infallible> {
+    let response: Responses = match route(request).await {
+        Ok(response) => response,
+        Err(e) => error_response(e, true),
+    };
+
+    let reply = hyper::Response::builder()
+        .status(http_status(response.status()))
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(json::render(&response, None)))
+        .expect("Static status and header always build");
+    Ok(reply)
+}
+
+// Each endpoint is one of the commands with its fields taken from the path and body
+async fn route(request: hyper::Request<Body>) -> ServerResult<Responses> {
+    let (parts, body) = request.into_parts();
+    let path: &str = parts.uri.path();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    let (command, mut args): (&str, Vec<String>) = match (&parts.method, segments.as_slice()) {
+        (&Method::POST, ["channels", name]) => ("CreateChannel", vec![name.to_string()]),
+        (&Method::DELETE, ["channels", name]) => ("DeleteChannel", vec![name.to_string()]),
+        (&Method::POST, ["channels", name, "registrations"]) => {
+            ("RegisterChannel", vec![name.to_string()])
+        }
+        (&Method::POST, ["channels", name, "messages"]) => ("Store", vec![name.to_string()]),
+        (&Method::GET, ["channels", name, "messages", "next"]) => {
+            ("Check", vec![name.to_string()])
+        }
+        (&Method::POST, ["channels", name, "messages", id, "ack"]) => {
+            ("Ack", vec![name.to_string(), id.to_string()])
+        }
+        (&Method::POST, ["channels", name, "messages", id, "nack"]) => {
+            ("Nack", vec![name.to_string(), id.to_string()])
+        }
+        (&Method::PUT, ["channels", name, "dead-letter", target]) => {
+            ("DeadLetter", vec![name.to_string(), target.to_string()])
+        }
+        (&Method::DELETE, ["channels", name, "dead-letter"]) => {
+            ("DeadLetter", vec![name.to_string()])
+        }
+        (&Method::POST, ["channels", name, "dead-letter", "replay"]) => {
+            ("Replay", vec![name.to_string()])
+        }
+        _ => {
+            return Err(ServerError::UnknownCommand(format!(
+                "{} {}",
+                parts.method, path
+            )))
+        }
+    };
+
+    // GET .../messages/next?wait=5000 long-polls like Check/mail_5000,
+    // POST .../registrations?group=workers joins like RegisterChannel/mail_workers,
+    // POST .../nack?delay=5000&reason=bounced hands back like Nack/mail_<id>_5000_bounced
+    // and PUT .../dead-letter/maildead?max_attempts=5 like DeadLetter/mail_maildead_5
+    let query: Option<&str> = parts.uri.query();
+    let options: Vec<Option<String>> = match command {
+        "Check" => vec![query_value(query, "wait")],
+        "RegisterChannel" => vec![query_value(query, "group")],
+        "Nack" => match query_value(query, "reason") {
+            Some(reason) => {
+                vec![query_value(query, "delay").or(Some(String::from("0"))), Some(reason)]
+            }
+            None => vec![query_value(query, "delay")],
+        },
+        "DeadLetter" => vec![query_value(query, "max_attempts")],
+        _ => Vec::new(),
+    };
+    args.extend(options.into_iter().flatten());
+
+    // Only a client that could pass verification gets to send us a body
+    preauthenticate(&parts.headers)?;
+
+    if command == "Store" {
+        let message: StoreBody = serde_json::from_slice(&read_body(body).await?)
+            .map_err(|e| ServerError::Malformed(format!("Bad message body: {}", e)))?;
+        args.extend([message.message_type, message.message, message.hash]);
+    }
+
+    let client: String = authenticate(&parts.headers, command, &args)?;
+    complex_processor(command, args, client).await
+}
+
+// Checks the client, timestamp and nonce headers, everything but the signature,
+// which needs the body
+fn preauthenticate(headers: &HeaderMap) -> ServerResult<()> {
+    let client: &str = client_header(headers)?;
+    match auth::plausible(
+        client,
+        header(headers, "x-ironpulse-timestamp"),
+        header(headers, "x-ironpulse-nonce"),
+    ) {
+        true => Ok(()),
+        false => Err(ServerError::Integrity(format!(
+            "HTTP request from {} refused on its headers",
+            client
+        ))),
+    }
+}
+
+// The same HMAC as the other encodings, taken over the text form of the command
+// the endpoint stands for: Store/mail_plain_6869_<hash>
+fn authenticate(headers: &HeaderMap, command: &str, args: &[String]) -> ServerResult<String> {
+    let client: &str = client_header(headers)?;
+
+    // The text form splits on underscores, so a field holding one, which a decoded
+    // query value can, would be signed as two
+    if let Some(arg) = args.iter().find(|arg| arg.contains('_')) {
+        return Err(ServerError::Malformed(format!(
+            "HTTP request field {:?} contains an underscore",
+            arg
+        )));
+    }
+
+    let signed: String = format!("{}/{}", command, args.join("_"));
+    match auth::verify(
+        client,
+        &signed,
+        header(headers, "x-ironpulse-signature").unwrap_or(""),
+        header(headers, "x-ironpulse-timestamp"),
+        header(headers, "x-ironpulse-nonce"),
+    )? {
+        true => Ok(client.to_string()),
+        false => Err(ServerError::Integrity(format!(
+            "HTTP request from {} failed verification",
+            client
+        ))),
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn client_header(headers: &HeaderMap) -> ServerResult<&str> {
+    header(headers, "x-ironpulse-client").ok_or_else(|| {
+        ServerError::Integrity(String::from("HTTP request without X-IronPulse-Client"))
+    })
+}
+
+// Query values are percent decoded, so ?reason=mailbox%20full reads as sent
+fn query_value(query: Option<&str>, name: &str) -> Option<String> {
+    form_urlencoded::parse(query?.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+// Bodies are held to the same limit as frames
+async fn read_body(mut body: Body) -> ServerResult<Vec<u8>> {
+    let limit: usize = config::get().limits.max_frame_size;
+    let mut bytes: Vec<u8> = Vec::new();
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk
+            .map_err(|e| ServerError::Malformed(format!("Couldn't read the body: {}", e)))?;
+        if bytes.len() + chunk.len() > limit {
+            return Err(ServerError::TooLarge(format!(
+                "HTTP body over the {} byte limit",
+                limit
+            )));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(bytes)
+}
+
+fn http_status(code: &StatCode) -> StatusCode {
+    match code {
+        StatCode::AckOk => StatusCode::OK,
+        StatCode::AckDr => StatusCode::CREATED,
+        StatCode::AckDs => StatusCode::OK,
+        StatCode::NoPer => StatusCode::FORBIDDEN,
+        StatCode::SecFt => StatusCode::UNAUTHORIZED,
+        StatCode::MalRq => StatusCode::BAD_REQUEST,
+        StatCode::UnCmd => StatusCode::NOT_FOUND,
+        StatCode::NoChn => StatusCode::NOT_FOUND,
+        StatCode::ChExs => StatusCode::CONFLICT,
+        StatCode::NoMsg => StatusCode::NOT_FOUND,
+        StatCode::Acked => StatusCode::CONFLICT,
+        StatCode::TooLg => StatusCode::PAYLOAD_TOO_LARGE,
+        StatCode::RtLmt => StatusCode::TOO_MANY_REQUESTS,
+        StatCode::NoHnd => StatusCode::INTERNAL_SERVER_ERROR,
+        StatCode::NoBkd => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
@@ -0,0 +1,166 @@
+use {
+    crate::{
+        auth,
+        error::{ServerError, ServerResult},
+        skel::{ChannelName, Payload, Request, RequestCode, RequestData, Responses, Integrity},
+    },
+    serde::Deserialize,
+    serde_json::{json, value::RawValue, Map, Value},
+};
+
+// A JSON connection sends one envelope per frame:
+// {"command": "Check", "request_id": "mailer-01", "payload": {"channel": "mail"},
+//  "auth": {"hash": "<hmac>", "ts": 1760000000, "nonce": "4f1c2a"}, "tag": "7"}
+#[derive(Deserialize)]
+struct Envelope<'a> {
+    command: String,
+    request_id: String,
+    #[serde(borrow, default)]
+    payload: Option<&'a RawValue>,
+    auth: Auth,
+    #[serde(default)]
+    tag: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Auth {
+    hash: String,
+    #[serde(default)]
+    ts: Option<Value>,
+    #[serde(default)]
+    nonce: Option<String>,
+}
+
+// The payload fields each command takes, in the order the handlers expect them
+fn payload_fields(command: &str) -> Option<&'static [&'static str]> {
+    match command {
+        "Hello" => Some(&["version", "capabilities"]),
+        "CreateChannel" | "DeleteChannel" => Some(&["channel"]),
+        "RegisterChannel" => Some(&["channel", "group"]),
+        "Check" => Some(&["channel", "wait_ms"]),
+        "Subscribe" => Some(&["channel", "max_in_flight"]),
+        "Unsubscribe" => Some(&["channel"]),
+        "Store" => Some(&["channel", "type", "message", "hash"]),
+        "Ack" => Some(&["channel", "id"]),
+        "Nack" => Some(&["channel", "id", "delay_ms", "reason"]),
+        "DeadLetter" => Some(&["channel", "dead_letter", "max_attempts"]),
+        "Replay" => Some(&["channel"]),
+        _ => None,
+    }
+}
+
+pub fn parse_request(frame: &str) -> ServerResult<Request> {
+    let envelope: Envelope = serde_json::from_str(frame)
+        .map_err(|e| ServerError::Malformed(format!("Bad JSON envelope: {}", e)))?;
+
+    // Numbers and strings are both fine for the timestamp, it is signed as written
+    let timestamp: Option<String> = match envelope.auth.ts {
+        Some(Value::String(ts)) => Some(ts),
+        Some(Value::Number(ts)) => Some(ts.to_string()),
+        Some(_) => return Err(ServerError::Malformed(String::from("auth.ts must be a number"))),
+        None => None,
+    };
+
+    // The client signs the payload exactly as it sent it, in the same place the
+    // text format has its data, and the tag as a text field would carry it:
+    // Command/{"channel":"mail"},tag=7
+    let mut signed: String = match envelope.payload {
+        Some(payload) => format!("{}/{}", envelope.command, payload.get()),
+        None => envelope.command.clone(),
+    };
+    if let Some(tag) = &envelope.tag {
+        signed.push_str(&format!(",tag={}", tag));
+    }
+    let integrity: bool = auth::verify(
+        &envelope.request_id,
+        &signed,
+        &envelope.auth.hash,
+        timestamp.as_deref(),
+        envelope.auth.nonce.as_deref(),
+    )?;
+
+    match envelope.payload {
+        Some(payload) => Ok(Request::Data(RequestData {
+            args: payload_args(&envelope.command, payload)?,
+            command: envelope.command,
+            requestid: envelope.request_id,
+            integrity,
+            tag: envelope.tag,
+        })),
+        None => Ok(Request::Code(RequestCode {
+            command: envelope.command,
+            requestid: envelope.request_id,
+            integrity,
+            tag: envelope.tag,
+        })),
+    }
+}
+
+// Lays the named payload fields out as the positional fields the handlers take.
+// Arrays are spread in place, so Hello's capabilities follow its version. Missing
+// fields and unknown commands are left for the handlers to reject, so the error
+// still goes out with the request's tag.
+fn payload_args(command: &str, payload: &RawValue) -> ServerResult<Vec<String>> {
+    let object: Map<String, Value> = serde_json::from_str(payload.get())
+        .map_err(|_| ServerError::Malformed(String::from("payload must be an object")))?;
+
+    let mut args: Vec<String> = Vec::new();
+    for field in payload_fields(command).unwrap_or(&[]) {
+        match object.get(*field) {
+            Some(Value::Array(values)) => args.extend(values.iter().map(scalar)),
+            Some(value) => args.push(scalar(value)),
+            None => (),
+        }
+    }
+
+    Ok(args)
+}
+
+// Strings are taken as they are, anything else as its JSON text
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+// {"status": 202, "data": "...", "integrity": "...", "id": "...", "reason": "...",
+// "tag": "7"}
+// with only the keys that apply to the response
+pub fn render(response: &Responses, tag: Option<&str>) -> String {
+    let mut object: Map<String, Value> = fields(response);
+    if let Some(tag) = tag {
+        object.insert(String::from("tag"), json!(tag));
+    }
+    Value::Object(object).to_string()
+}
+
+// Something the server sends without being asked, named by the channel it is about
+pub fn render_event(channel: &ChannelName, response: &Responses) -> String {
+    let mut object: Map<String, Value> = fields(response);
+    object.insert(String::from("channel"), json!(channel.as_str()));
+    Value::Object(object).to_string()
+}
+
+fn fields(response: &Responses) -> Map<String, Value> {
+    let mut object: Map<String, Value> = Map::new();
+
+    match response {
+        Responses::Code(_) => (),
+        Responses::Data(_, Payload::Data(data, Integrity::Hash(hash))) => {
+            object.insert(String::from("data"), json!(data));
+            object.insert(String::from("integrity"), json!(hash));
+        }
+        Responses::Data(_, Payload::Hex(data, Integrity::Hash(hash), id)) => {
+            object.insert(String::from("data"), json!(data));
+            object.insert(String::from("integrity"), json!(hash));
+            object.insert(String::from("id"), json!(id));
+        }
+        Responses::Reason(_, reason) => {
+            object.insert(String::from("reason"), json!(reason));
+        }
+    };
+    object.insert(String::from("status"), json!(response.status().code()));
+
+    object
+}
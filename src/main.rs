@@ -1,96 +1,386 @@
+pub mod auth;
+pub mod binary;
 pub mod commands;
+pub mod config;
 pub mod database;
+pub mod error;
 pub mod functions;
+pub mod http;
+pub mod json;
+pub mod notify;
+pub mod protocol;
 pub mod skel;
+pub mod storage;
+pub mod subscription;
+#[cfg(test)]
+mod testing;
+pub mod tls;
+pub mod unix;
+pub mod ws;
 
 use {
     commands::{complex_processor, simple_processor},
-    functions::sec_fault,
+    error::{ServerError, ServerResult},
+    functions::{frame_text, read_frame, respond, write_frame},
     logging::{append_log, start_log},
-    skel::{Request, RequestCode, RequestData},
-    std::{
-        io::Read,
+    skel::{Client, Encoding, Request, RequestCode, RequestData, Responses, Session},
+    subscription::Subscriptions,
+    openssl::ssl::SslAcceptor,
+    std::{env, process::ExitCode, sync::Arc, time::Duration},
+    tokio::{
+        io::{AsyncRead, AsyncWrite, WriteHalf},
         net::{TcpListener, TcpStream},
-        thread,
+        signal::unix::{signal, SignalKind},
+        sync::{
+            mpsc::{self, Receiver},
+            watch, Semaphore,
+        },
+        task::JoinSet,
+        time::timeout,
     },
-    system::create_hash,
 };
 
 pub const PROG: &str = "IronPulse_server";
 
-fn main() {
+// Returning the exit code instead of exiting lets the runtime and the logger wind
+// down properly, so the last log lines make it out
+#[tokio::main]
+async fn main() -> ExitCode {
     start_log(PROG);
 
-    let listen_addr = "0.0.0.0:9518"; // Change this to your desired address and port
+    // The only argument is the path to the yaml config file
+    let config_path: Option<String> = env::args().nth(1);
+    let config = match config::load(config_path.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            append_log(PROG, &format!("Invalid configuration: {}", e));
+            eprintln!("Invalid configuration:\n{}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    if let Err(e) = auth::init() {
+        append_log(PROG, &format!("Couldn't load client keys: {}", e));
+        eprintln!("Couldn't load client keys: {}", e);
+        return ExitCode::from(2);
+    }
+
+    if let Err(e) = storage::init() {
+        append_log(PROG, &format!("Couldn't open storage: {}", e));
+        eprintln!("Couldn't open storage: {}", e);
+        return ExitCode::from(2);
+    }
 
-    let tcp_listener = match TcpListener::bind(listen_addr) {
+    let tls_acceptor: Option<Arc<SslAcceptor>> = match tls::acceptor() {
+        Ok(acceptor) => acceptor.map(Arc::new),
+        Err(e) => {
+            append_log(PROG, &format!("Couldn't set up tls: {}", e));
+            eprintln!("Couldn't set up tls: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    let listen_addr: &str = &config.listener.address;
+
+    let tcp_listener = match TcpListener::bind(listen_addr).await {
         Ok(tcp) => tcp,
         Err(e) => {
             append_log(PROG, &format!("Couldn't create tcp listener: {}", e));
-            panic!("Listeing failed");
+            eprintln!("Couldn't listen on {}: {}", listen_addr, e);
+            return ExitCode::from(2);
         }
     };
 
     append_log(PROG, &format!("Listening on {}", listen_addr));
 
-    // Create a vector to store client handler threads
-    let mut client_handlers = vec![];
+    let http_gateway = match http::bind() {
+        Ok(gateway) => gateway,
+        Err(e) => {
+            append_log(PROG, &format!("Couldn't start the http gateway: {}", e));
+            eprintln!("Couldn't start the http gateway: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    let websocket = match ws::bind().await {
+        Ok(listener) => listener,
+        Err(e) => {
+            append_log(PROG, &format!("Couldn't start the websocket listener: {}", e));
+            eprintln!("Couldn't start the websocket listener: {}", e);
+            return ExitCode::from(2);
+        }
+    };
 
-    for stream_result in tcp_listener.incoming() {
-        match stream_result {
-            Ok(tcp_stream) => {
-                let client_handlers_ref = &mut client_handlers;
+    let unix_socket = match unix::bind() {
+        Ok(listener) => listener,
+        Err(e) => {
+            append_log(PROG, &format!("Couldn't start the unix socket: {}", e));
+            eprintln!("Couldn't start the unix socket: {}", e);
+            return ExitCode::from(2);
+        }
+    };
 
-                // Spawn a new thread to handle each client
-                let handle = thread::spawn(move || {
-                    handle_stream(tcp_stream);
-                });
+    // Flipped to true once SIGTERM or SIGINT arrives
+    let shutdown: watch::Receiver<bool> = notify::shutdown();
+    tokio::spawn(async move {
+        let signal_name: &str = shutdown_signal().await;
+        append_log(PROG, &format!("Recived {}, shutting down", signal_name));
+        notify::shut_down();
+    });
 
-                client_handlers_ref.push(handle);
-            }
-            Err(err) => {
-                append_log(PROG, &format!("Error accepting connection: {}", err));
-                eprintln!("Check log");
-                break;
-            }
+    let mut connections: JoinSet<()> = JoinSet::new();
+    let mut accept_shutdown = shutdown.clone();
+
+    // The other listeners drain with the connections when we shut down
+    if let Some(gateway) = http_gateway {
+        connections.spawn(http::serve(gateway, shutdown.clone()));
+    }
+    if let Some(listener) = websocket {
+        connections.spawn(ws::serve(listener, shutdown.clone()));
+    }
+    if let Some(listener) = unix_socket {
+        connections.spawn(unix::serve(listener, shutdown.clone()));
+    }
+
+    loop {
+        tokio::select! {
+            stream_result = tcp_listener.accept() => match stream_result {
+                Ok((tcp_stream, _)) => {
+                    // Each client is a task, idle connections cost no thread
+                    connections.spawn(serve(tcp_stream, tls_acceptor.clone(), shutdown.clone()));
+                }
+                Err(err) => {
+                    // Usually running out of file descriptors, keep serving the clients we have
+                    append_log(PROG, &format!("Error accepting connection: {}", err));
+                    eprintln!("Check log");
+                }
+            },
+            // Reaping finished connections so the set only holds live ones
+            Some(_) = connections.join_next(), if !connections.is_empty() => (),
+            _ = accept_shutdown.changed() => break,
         }
     }
 
-    // Wait for all client handler threads to finish
-    for handle in client_handlers {
-        handle.join().expect("Client handler thread panicked");
+    // No new clients from here on, the open ones finish what they started
+    drop(tcp_listener);
+    let shutdown_timeout: Duration = Duration::from_secs(config.limits.shutdown_timeout);
+    append_log(
+        PROG,
+        &format!(
+            "Draining {} connections, waiting up to {} seconds",
+            connections.len(),
+            shutdown_timeout.as_secs()
+        ),
+    );
+
+    let drained = timeout(shutdown_timeout, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+
+    match drained {
+        Ok(_) => {
+            append_log(PROG, "All connections drained, shutdown complete");
+            ExitCode::SUCCESS
+        }
+        Err(_) => {
+            append_log(
+                PROG,
+                &format!(
+                    "Shutdown deadline passed, aborting {} connections",
+                    connections.len()
+                ),
+            );
+            connections.abort_all();
+            eprintln!("Shutdown deadline passed, check log");
+            ExitCode::FAILURE
+        }
     }
 }
 
-fn handle_stream(mut tcp_stream: TcpStream) {
-    // Input
-    let mut request = String::new();
-    tcp_stream
-        .read_to_string(&mut request)
-        .expect("Failed at reading the unix stream");
+// Resolves with the name of the first termination signal we get
+async fn shutdown_signal() -> &'static str {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Couldn't listen for SIGTERM");
+    let mut sigint = signal(SignalKind::interrupt()).expect("Couldn't listen for SIGINT");
 
-    // println!("Client Command: {}\nAck", request);
-    // notice("Data recived");
-    let request: Request = phrasing_request(request.clone()).unwrap();
+    tokio::select! {
+        _ = sigterm.recv() => "SIGTERM",
+        _ = sigint.recv() => "SIGINT",
+    }
+}
 
-    let integrity: bool = match &request {
-        Request::Code(d) => d.integrity,
-        Request::Data(d) => d.integrity,
+// Runs the tls handshake when it's turned on, then serves the connection
+async fn serve(
+    tcp_stream: TcpStream,
+    tls_acceptor: Option<Arc<SslAcceptor>>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let acceptor: Arc<SslAcceptor> = match tls_acceptor {
+        Some(acceptor) => acceptor,
+        None => return handle_stream(tcp_stream, None, shutdown).await,
     };
 
-    if !integrity {
-        sec_fault(&tcp_stream);
-        return;
+    // A client that stalls the handshake would otherwise hold the connection, and
+    // the drain, forever
+    let handshake_timeout = Duration::from_secs(config::get().tls.handshake_timeout);
+    let accepted = tokio::select! {
+        accepted = timeout(handshake_timeout, tls::accept(&acceptor, tcp_stream)) => accepted,
+        _ = shutdown.changed() => return,
+    };
+
+    match accepted {
+        Ok(Ok((tls_stream, identity))) => handle_stream(tls_stream, identity, shutdown).await,
+        Ok(Err(e)) => append_log(PROG, &format!("Dropping connection: {}", e)),
+        Err(_) => append_log(PROG, "Dropping connection: tls handshake timed out"),
+    }
+}
+
+// identity is the registration id the transport vouched for, if any
+async fn handle_stream<S>(stream: S, identity: Option<String>, mut shutdown: watch::Receiver<bool>)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut read_stream, write_stream) = tokio::io::split(stream);
+
+    // Responses are queued to a writer task so pipelined requests can finish in any order.
+    // Both the queue and the tagged requests running are bounded, so a client that
+    // sends faster than it reads only slows itself down.
+    let max_pipelined: usize = config::get().limits.max_pipelined;
+    let (sender, receiver) = mpsc::channel::<Vec<u8>>(max_pipelined);
+    let writer = tokio::spawn(write_responses(write_stream, receiver));
+    let pipelined: Arc<Semaphore> = Arc::new(Semaphore::new(max_pipelined));
+
+    // Every connection speaks version 0 until a Hello says otherwise
+    let mut session: Arc<Session> = Arc::new(Session::default());
+    // Set by the first frame, text until then
+    let mut encoding: Option<Encoding> = None;
+
+    // Channels this connection has subscribed to, pushed on the same writer
+    let mut subscriptions: Subscriptions = Subscriptions::default();
+
+    // For answering when there is no request to take a tag from
+    let untagged = |session: &Arc<Session>, encoding: Option<Encoding>| Client {
+        sender: sender.clone(),
+        tag: None,
+        identity: identity.clone(),
+        session: Arc::clone(session),
+        encoding: encoding.unwrap_or(Encoding::Text),
+    };
+
+    // Reading frames until the client closes the connection or we shut down
+    loop {
+        let frame = tokio::select! {
+            frame = read_frame(&mut read_stream) => frame,
+            _ = shutdown.changed() => break,
+        };
+
+        let request: Vec<u8> = match frame {
+            Ok(Some(data)) => data,
+            Ok(None) => break,
+            Err(e) => {
+                append_log(PROG, &format!("Failed at reading the unix stream: {}", e));
+                // An oversized frame is answered before we hang up, the rest of it is never read
+                if let Some(Ok(error)) = e.into_inner().map(|e| e.downcast::<ServerError>()) {
+                    respond(Err(*error), &untagged(&session, encoding)).await;
+                }
+                break;
+            }
+        };
+
+        // println!("Client Command: {}\nAck", request);
+        // notice("Data recived");
+        let current: Encoding = *encoding.get_or_insert_with(|| protocol::detect(&request));
+        let parsed: ServerResult<Request> = match current {
+            Encoding::Text => frame_text(request).and_then(phrasing_request),
+            Encoding::Json => frame_text(request).and_then(|request| json::parse_request(&request)),
+            Encoding::Binary => binary::parse_request(&request),
+        };
+        let request: Request = match parsed {
+            Ok(request) => request,
+            Err(e) => {
+                // Nothing to echo a tag from, the client gets a bare status code
+                respond(Err(e), &untagged(&session, encoding)).await;
+                continue;
+            }
+        };
+
+        let tag: Option<String> = match &request {
+            Request::Code(data) => data.tag.clone(),
+            Request::Data(data) => data.tag.clone(),
+        };
+
+        let client: Client = Client {
+            sender: sender.clone(),
+            tag,
+            identity: identity.clone(),
+            session: Arc::clone(&session),
+            encoding: current,
+        };
+
+        // Hello changes how everything after it is answered, so it is never pipelined
+        if protocol::is_hello(&request) {
+            if let Some(negotiated) = hello(&request, &client).await {
+                session = Arc::new(negotiated);
+            }
+            continue;
+        }
+
+        // Never pipelined, a subscription is answered before its first push
+        if subscription::handles(&request) {
+            subscriptions.request(&request, &client).await;
+            continue;
+        }
+
+        match client.tag {
+            // Tagged requests are pipelined and may be answered out of order. Nothing
+            // more is read while all the slots are taken.
+            Some(_) => {
+                let slot = Arc::clone(&pipelined)
+                    .acquire_owned()
+                    .await
+                    .expect("The pipeline semaphore is never closed");
+                tokio::spawn(async move {
+                    respond(handle_request(request, &client).await, &client).await;
+                    drop(slot);
+                });
+            }
+            // Untagged requests keep the one at a time ordering
+            None => respond(handle_request(request, &client).await, &client).await,
+        }
     }
 
+    // The writer finishes once every pipelined request has answered and every
+    // subscription has stopped, each dropping its sender
+    drop(subscriptions);
+    drop(sender);
+    if writer.await.is_err() {
+        append_log(PROG, "Response writer task panicked");
+    }
+}
+
+async fn write_responses<S: AsyncWrite>(
+    mut write_stream: WriteHalf<S>,
+    mut receiver: Receiver<Vec<u8>>,
+) {
+    while let Some(response) = receiver.recv().await {
+        if let Err(e) = write_frame(&response, &mut write_stream).await {
+            append_log(PROG, &format!("Failed at writing onto the unix stream: {}", e));
+            break;
+        }
+    }
+}
+
+async fn handle_request(request: Request, client: &Client) -> ServerResult<Responses> {
+    authorize(&request, client)?;
+
     let command: String = match &request {
         Request::Code(data) => data.command.to_string(),
         Request::Data(data) => data.command.to_string(),
     };
 
-    let command_string: Option<String> = match &request {
+    let command_args: Option<Vec<String>> = match &request {
         Request::Code(_) => None,
-        Request::Data(data) => Some(data.data.to_string()),
+        Request::Data(data) => Some(data.args.clone()),
     };
 
     let registration_id: String = match &request {
@@ -99,14 +389,78 @@ fn handle_stream(mut tcp_stream: TcpStream) {
     };
 
     // processing the code
-    match command_string {
-        Some(d) => complex_processor(&command, d, registration_id, &tcp_stream),
-        _ => simple_processor(&command, registration_id, &tcp_stream),
+    match command_args {
+        Some(d) => complex_processor(&command, d, registration_id).await,
+        _ => simple_processor(&command, registration_id).await,
+    }
+}
+
+// Answers a Hello on any listener. The session it agreed on, if it did, which
+// everything after it on the connection is answered with.
+async fn hello(request: &Request, client: &Client) -> Option<Session> {
+    // A Hello without fields has no version, negotiate refuses it as malformed
+    let (requestid, args): (&str, &[String]) = match request {
+        Request::Code(data) => (&data.requestid, &[]),
+        Request::Data(data) => (&data.requestid, &data.args),
+    };
+
+    match authorize(request, client).and_then(|_| protocol::negotiate(args)) {
+        Ok(negotiated) => {
+            append_log(
+                PROG,
+                &format!(
+                    "Client {} speaks protocol {} with {:?}",
+                    requestid, negotiated.version, negotiated.capabilities
+                ),
+            );
+            respond(Ok(protocol::hello_response(&negotiated)), client).await;
+            Some(negotiated)
+        }
+        Err(e) => {
+            respond(Err(e), client).await;
+            None
+        }
+    }
+}
+
+// The request has to have passed its integrity check, and on a pinned connection it
+// has to come from the client the connection belongs to
+fn authorize(request: &Request, client: &Client) -> ServerResult<()> {
+    let integrity: bool = match request {
+        Request::Code(d) => d.integrity,
+        Request::Data(d) => d.integrity,
+    };
+
+    if !integrity {
+        return Err(ServerError::Integrity(String::from("request failed verification")));
     }
+
+    let registration_id: &str = match request {
+        Request::Code(data) => &data.requestid,
+        Request::Data(data) => &data.requestid,
+    };
+
+    // A client certificate or a mapped unix uid decides who the client is
+    if let Some(identity) = &client.identity {
+        if identity != registration_id {
+            return Err(ServerError::Integrity(format!(
+                "Connection pinned to {} used to send requests as {}",
+                identity, registration_id
+            )));
+        }
+    }
+
+    Ok(())
 }
 
-fn phrasing_request(data: String) -> Option<Request> {
+fn phrasing_request(data: String) -> ServerResult<Request> {
     let split_data: Vec<String> = data.split(',').map(|s| s.to_string()).collect();
+    if split_data.len() < 3 {
+        return Err(ServerError::Malformed(format!(
+            "expected command, id and hash, got {} fields",
+            split_data.len()
+        )));
+    }
     let split_request: Vec<String> = split_data[0].split('/').map(|s| s.to_string()).collect();
 
     let request_command: String = split_request[0].to_string();
@@ -118,32 +472,61 @@ fn phrasing_request(data: String) -> Option<Request> {
 
     let registration_id: String = split_data[1].to_string();
     let integrity_source: String = split_data[2].to_string();
-    let integrity_match: String = create_hash(&format!("{}{}", split_data[1], split_data[0]));
+
+    // Optional name=value fields after the hash
+    let mut tag: Option<String> = None;
+    let mut timestamp: Option<String> = None;
+    let mut nonce: Option<String> = None;
+    for field in split_data.iter().skip(3) {
+        if let Some(value) = field.strip_prefix("tag=") {
+            tag = Some(value.to_string());
+        } else if let Some(value) = field.strip_prefix("ts=") {
+            timestamp = Some(value.to_string());
+        } else if let Some(value) = field.strip_prefix("nonce=") {
+            nonce = Some(value.to_string());
+        }
+    }
+
+    // Everything but the id, the hash, ts and nonce is signed, the tag and any other
+    // trailing fields included, so they can't be changed on a captured request
+    let signed: String = split_data
+        .iter()
+        .enumerate()
+        .filter(|(index, field)| {
+            *index == 0 || (*index > 2 && !field.starts_with("ts=") && !field.starts_with("nonce="))
+        })
+        .map(|(_, field)| field.as_str())
+        .collect::<Vec<&str>>()
+        .join(",");
 
     // Running the integrity Testing
-    let integrity_check = if integrity_match == integrity_source {
-        true
-    } else {
-        false
-    };
+    let integrity_check: bool = auth::verify(
+        &registration_id,
+        &signed,
+        &integrity_source,
+        timestamp.as_deref(),
+        nonce.as_deref(),
+    )?;
 
     match request_string {
         Some(d) => {
             let request_data: RequestData = RequestData {
                 command: request_command,
-                data: d,
+                args: d.split('_').map(|s| s.to_string()).collect(),
                 requestid: registration_id,
                 integrity: integrity_check,
+                tag,
             };
-            return Some(Request::Data(request_data));
+            Ok(Request::Data(request_data))
         }
         _ => {
             let request_code: RequestCode = RequestCode {
                 command: request_command,
                 requestid: registration_id,
                 integrity: integrity_check,
+                tag,
             };
-            return Some(Request::Code(request_code));
+            Ok(Request::Code(request_code))
         }
     }
 }
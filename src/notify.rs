@@ -0,0 +1,147 @@
+use {
+    crate::skel::ChannelName,
+    std::{
+        collections::{BTreeSet, HashMap},
+        sync::{Arc, Mutex, OnceLock},
+        time::Duration,
+    },
+    tokio::{
+        sync::{watch, Notify},
+        time::{self, Instant},
+    },
+};
+
+// Wakes whoever is waiting on a channel when its queue changes. Each channel has a
+// counter that goes up on every store and ack, so waiters re-read the queue
+// instead of being handed messages, and storage stays the only source of truth.
+static CHANNELS: OnceLock<Mutex<HashMap<String, watch::Sender<u64>>>> = OnceLock::new();
+
+fn channels() -> std::sync::MutexGuard<'static, HashMap<String, watch::Sender<u64>>> {
+    CHANNELS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .expect("Notify lock poisoned")
+}
+
+// Marks the current state seen before reading the queue, so a change made between
+// the read and the wait is never missed
+pub fn watch(channel: &ChannelName) -> watch::Receiver<u64> {
+    let mut channels = channels();
+    let sender = channels
+        .entry(channel.as_str().to_string())
+        .or_insert_with(|| watch::channel(0).0);
+    sender.subscribe()
+}
+
+pub fn wake(channel: &ChannelName) {
+    if let Some(sender) = channels().get(channel.as_str()) {
+        sender.send_modify(|changes| *changes = changes.wrapping_add(1));
+    }
+}
+
+// Pending wake_after deadlines of a channel. One task per channel sleeps until the
+// earliest of them, rescheduled tells it an even earlier one came in.
+struct Timer {
+    deadlines: BTreeSet<Instant>,
+    rescheduled: Arc<Notify>,
+}
+
+static TIMERS: OnceLock<Mutex<HashMap<String, Timer>>> = OnceLock::new();
+
+fn timers() -> std::sync::MutexGuard<'static, HashMap<String, Timer>> {
+    TIMERS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .expect("Timer lock poisoned")
+}
+
+// For changes nobody makes, like a lease running out and its message becoming
+// visible again
+pub fn wake_after(channel: &ChannelName, delay: Duration) {
+    let deadline: Instant = Instant::now() + delay;
+    let mut timers = timers();
+
+    match timers.get_mut(channel.as_str()) {
+        Some(timer) => {
+            if timer.deadlines.first().is_none_or(|first| deadline < *first) {
+                timer.rescheduled.notify_one();
+            }
+            timer.deadlines.insert(deadline);
+        }
+        None => {
+            let rescheduled: Arc<Notify> = Arc::new(Notify::new());
+            timers.insert(
+                channel.as_str().to_string(),
+                Timer {
+                    deadlines: BTreeSet::from([deadline]),
+                    rescheduled: Arc::clone(&rescheduled),
+                },
+            );
+            tokio::spawn(run_timer(channel.clone(), rescheduled));
+        }
+    }
+}
+
+// Wakes the channel at each of its deadlines, every one passed by then is covered
+// by the same wake. Stops once there are none left, the next wake_after starts a
+// new one.
+async fn run_timer(channel: ChannelName, rescheduled: Arc<Notify>) {
+    loop {
+        let next: Instant = match timers().get(channel.as_str()) {
+            Some(timer) => match timer.deadlines.first() {
+                Some(next) => *next,
+                None => return,
+            },
+            None => return,
+        };
+
+        tokio::select! {
+            _ = time::sleep_until(next) => (),
+            _ = rescheduled.notified() => continue,
+        }
+
+        // Once the timer is out of the map a wake_after starts a new one, so this one
+        // must not look at the map again
+        let finished: bool = {
+            let mut timers = timers();
+            match timers.get_mut(channel.as_str()) {
+                Some(timer) => {
+                    let now: Instant = Instant::now();
+                    timer.deadlines.retain(|deadline| *deadline > now);
+                    let finished: bool = timer.deadlines.is_empty();
+                    if finished {
+                        timers.remove(channel.as_str());
+                    }
+                    finished
+                }
+                None => true,
+            }
+        };
+        wake(&channel);
+        if finished {
+            return;
+        }
+    }
+}
+
+// Nobody can be waiting on a channel that's gone. Its timer is left to run out, so
+// there's never more than one per channel.
+pub fn forget(channel: &ChannelName) {
+    channels().remove(channel.as_str());
+}
+
+// Flipped to true once the server starts shutting down, so waits that could
+// outlast the drain give up instead
+static SHUTDOWN: OnceLock<watch::Sender<bool>> = OnceLock::new();
+
+fn shutdown_sender() -> &'static watch::Sender<bool> {
+    SHUTDOWN.get_or_init(|| watch::channel(false).0)
+}
+
+pub fn shutdown() -> watch::Receiver<bool> {
+    shutdown_sender().subscribe()
+}
+
+pub fn shut_down() {
+    shutdown_sender().send_replace(true);
+}
@@ -0,0 +1,82 @@
+use crate::{
+    binary,
+    error::{ServerError, ServerResult},
+    functions::ack_ds,
+    skel::{Encoding, Request, Responses, Session},
+};
+
+// The newest protocol version this server speaks. Version 0 is the original
+// unversioned format and is what every connection starts on.
+pub const VERSION: u32 = 1;
+
+// Optional behaviour a client can ask for in its Hello
+//   tags     tag= fields are echoed back and tagged requests may be pipelined,
+//            honoured on every version and listed so clients can detect it
+//   reasons  error codes carry a ,reason= field
+pub const CAPABILITIES: [&str; 2] = ["tags", "reasons"];
+
+// The first frame tells us how the client frames everything else. Text requests
+// start with a command name, JSON with a brace and binary with its magic byte.
+pub fn detect(frame: &[u8]) -> Encoding {
+    match frame.iter().find(|byte| !byte.is_ascii_whitespace()) {
+        Some(&binary::MAGIC) => Encoding::Binary,
+        Some(b'{') => Encoding::Json,
+        _ => Encoding::Text,
+    }
+}
+
+impl Session {
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+// Hello is answered by the listener itself, whether or not it carries fields
+pub fn is_hello(request: &Request) -> bool {
+    let command: &str = match request {
+        Request::Code(data) => &data.command,
+        Request::Data(data) => &data.command,
+    };
+    command == "Hello"
+}
+
+// Hello fields are the client's version followed by the capabilities it wants,
+// in the text format Hello/1_tags_reasons
+// The session gets the lower of the two versions and the capabilities both sides
+// know. Naming none asks for everything the server has.
+pub fn negotiate(args: &[String]) -> ServerResult<Session> {
+    let mut fields = args.iter().map(|field| field.as_str());
+
+    let requested: u32 = match fields.next().map(|version| version.parse::<u32>()) {
+        Some(Ok(version)) => version,
+        _ => {
+            return Err(ServerError::Malformed(format!(
+                "Hello needs a numeric version, got {:?}",
+                args.first()
+            )))
+        }
+    };
+    let version: u32 = requested.min(VERSION);
+
+    let wanted: Vec<&str> = fields.filter(|cap| !cap.is_empty()).collect();
+    let capabilities: Vec<String> = match version {
+        0 => Vec::new(), // version 0 predates capabilities
+        _ => CAPABILITIES
+            .iter()
+            .filter(|cap| wanted.is_empty() || wanted.contains(cap))
+            .map(|cap| cap.to_string())
+            .collect(),
+    };
+
+    Ok(Session {
+        version,
+        capabilities,
+    })
+}
+
+// The reply mirrors the request, the agreed version and capabilities
+pub fn hello_response(session: &Session) -> Responses {
+    let mut fields: Vec<String> = vec![session.version.to_string()];
+    fields.extend(session.capabilities.iter().cloned());
+    ack_ds(fields.join("_"))
+}
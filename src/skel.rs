@@ -1,9 +1,11 @@
-use std::fmt;
+use std::{fmt, sync::Arc};
+use tokio::sync::mpsc::Sender;
 use serde::{Deserialize, Serialize};
 
 pub enum Responses {
     Code(StatCode),
     Data(StatCode, Payload),
+    Reason(StatCode, String), // code with a short machine readable reason
 }
 
 #[allow(dead_code)]
@@ -14,6 +16,15 @@ pub enum StatCode {
     NoHnd, // Resource not foure or err occoured
     NoPer, // Invalid permission or registration
     SecFt, // Integrity check failed
+    MalRq, // Request or payload couldn't be parsed
+    UnCmd, // Command isn't one we handle
+    NoChn, // Channel doesn't exist
+    ChExs, // Channel already exists
+    NoMsg, // Message id unknown on the channel
+    Acked, // Message was already acknowledged
+    TooLg, // Frame or field over the size limit
+    RtLmt, // Too many requests, back off and retry
+    NoBkd, // Storage backend unavailable, retry later
 }
 
 pub enum Request {
@@ -23,20 +34,53 @@ pub enum Request {
 
 pub struct RequestData {
     pub command: String,
-    pub data: String,
+    pub args: Vec<String>, // the payload fields, underscore separated in the text format
     pub requestid: String,
     pub integrity: bool,
+    pub tag: Option<String>,
 }
 
 pub struct RequestCode {
     pub command: String,
     pub requestid: String,
     pub integrity: bool,
+    pub tag: Option<String>,
+}
+
+// Where a response has to go. The sender feeds the connection's writer task and
+// is shared by every request in flight on it, the tag is echoed back so pipelined
+// clients can match responses to the requests that caused them. identity is set
+// when the transport already proved who the client is (a tls client certificate).
+// The sender is bounded, so a client that doesn't read holds up whoever answers it.
+pub struct Client {
+    pub sender: Sender<Vec<u8>>,
+    pub tag: Option<String>,
+    pub identity: Option<String>,
+    pub session: Arc<Session>,
+    pub encoding: Encoding,
+}
+
+// How a connection frames its requests and responses, picked from its first frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Text, // Command/data,id,hash
+    Json, // one JSON object per frame
+    Binary, // fixed header and typed fields, see docs/binary-protocol.md
+}
+
+// What the connection agreed on in its Hello. Connections that never send one
+// stay on version 0, the original format, with no capabilities.
+#[derive(Clone, Debug, Default)]
+pub struct Session {
+    pub version: u32,
+    pub capabilities: Vec<String>,
 }
 
 
 pub enum Payload {
     Data(String, Integrity),
+    // hex encoded bytes and the message id, binary connections get the bytes raw
+    Hex(String, Integrity, String),
 }
 
 pub enum Integrity {
@@ -51,10 +95,18 @@ pub struct Email {
     pub body: String,
 }
 
+// A channel name that passed validation. Table names are only ever built from
+// one of these, never from raw client input.
+#[derive(Clone, Debug)]
+pub struct ChannelName(String);
+
+#[derive(Clone)]
 pub struct Message {
-    pub uuid: String,
+    pub uuid: String, // the id clients ack it by, made up when it's stored
     pub message_type: String,
     pub message: String,
+    pub hash: String, // what the producer stored it with, checked before it goes out
+    pub attempts: u32, // times it was handed out to the reader, this time included
 }
 
 // Database credential struct
@@ -66,6 +118,48 @@ pub struct Database {
 }
 
 // Implementations
+impl ChannelName {
+    pub const MAX_LENGTH: usize = 48;
+
+    // A letter followed by letters or digits, at most MAX_LENGTH long. That keeps
+    // every table derived from it a plain identifier under MySQL's 64 char limit.
+    pub fn parse(name: &str) -> Option<ChannelName> {
+        let mut chars = name.chars();
+        let valid: bool = match chars.next() {
+            Some(first) => {
+                first.is_ascii_alphabetic()
+                    && chars.all(|c| c.is_ascii_alphanumeric())
+                    && name.len() <= Self::MAX_LENGTH
+            }
+            None => false,
+        };
+
+        match valid {
+            true => Some(ChannelName(name.to_string())),
+            false => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    // Quoted identifiers, backticks work for both MySQL and SQLite
+    pub fn message_table(&self) -> String {
+        format!("`{}`", self.0)
+    }
+
+    pub fn permission_table(&self) -> String {
+        format!("`{}_permission`", self.0)
+    }
+}
+
+impl fmt::Display for ChannelName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 impl fmt::Display for Request {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -77,7 +171,7 @@ impl fmt::Display for Request {
 
 impl fmt::Display for RequestData {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            write!(f, "{},{},{},{}", self.command, self.data, self.requestid, self.integrity )
+            write!(f, "{},{},{},{}", self.command, self.args.join("_"), self.requestid, self.integrity )
     }
 }
 
@@ -92,27 +186,54 @@ impl fmt::Display for Responses {
         match self {
             Responses::Code(code) => write!(f, "{}", code),
             Responses::Data(code, data) => write!(f, "{},{}", code, data),
+            Responses::Reason(code, reason) => write!(f, "{},reason={}", code, reason),
         }
     }
 }
 
-impl fmt::Display for StatCode {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl Responses {
+    pub fn status(&self) -> &StatCode {
         match self {
-            StatCode::AckOk => write!(f, "200"), // ok
-            StatCode::AckDr => write!(f, "201"), // ack recived data
-            StatCode::AckDs => write!(f, "202"), // ack data in response
-            StatCode::NoPer => write!(f, "400"), // client messed up
-            StatCode::NoHnd => write!(f, "500"), // i messed up
-            StatCode::SecFt => write!(f, "520"), // i refuse, security fault
+            Responses::Code(code) => code,
+            Responses::Data(code, _) => code,
+            Responses::Reason(code, _) => code,
         }
     }
 }
 
+impl StatCode {
+    pub fn code(&self) -> u16 {
+        match self {
+            StatCode::AckOk => 200, // ok
+            StatCode::AckDr => 201, // ack recived data
+            StatCode::AckDs => 202, // ack data in response
+            StatCode::NoPer => 400, // client messed up
+            StatCode::NoHnd => 500, // i messed up
+            StatCode::SecFt => 520, // i refuse, security fault
+            StatCode::NoChn => 404, // no such channel
+            StatCode::UnCmd => 405, // no such command
+            StatCode::ChExs => 409, // channel already there
+            StatCode::NoMsg => 406, // no such message
+            StatCode::Acked => 407, // message already acked
+            StatCode::MalRq => 410, // couldn't read the request
+            StatCode::TooLg => 413, // too big
+            StatCode::RtLmt => 429, // slow down, try again later
+            StatCode::NoBkd => 503, // storage is down, try again later
+        }
+    }
+}
+
+impl fmt::Display for StatCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
 impl fmt::Display for Payload {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Payload::Data(data, sec) => write!(f, "{}/{}", data, sec),
+            Payload::Hex(data, sec, id) => write!(f, "{}/{},id={}", data, sec, id),
         }
     }
 }
@@ -0,0 +1,422 @@
+use {
+    super::{Fetched, Storage},
+    crate::{
+        error::ServerError,
+        skel::{ChannelName, Message},
+    },
+    anyhow::{anyhow, Result},
+    std::{
+        collections::{HashMap, HashSet, VecDeque},
+        sync::{Mutex, MutexGuard},
+        time::Duration,
+    },
+};
+
+// Keeps everything in process, nothing survives a restart. Meant for development
+// and for testing the server without a database.
+#[derive(Default)]
+pub struct MemoryStorage {
+    channels: Mutex<HashMap<String, Channel>>,
+}
+
+#[derive(Default)]
+struct Channel {
+    messages: VecDeque<Message>,
+    permissions: HashSet<String>,
+    groups: HashMap<String, String>, // consumer to its group
+    // message uuid to what each reader was handed, like ironpulse_deliveries
+    deliveries: HashMap<String, HashMap<String, Delivery>>,
+    dead_letter: Option<(ChannelName, u32)>, // where messages go after max attempts
+    // message uuid to the channels that buried it here, like ironpulse_buried
+    buried: HashMap<String, HashSet<String>>,
+    // removed message uuid to when its deliveries go, until then an ack is remembered
+    settled: HashMap<String, i64>,
+}
+
+struct Delivery {
+    member: String,
+    visible_at: i64, // milliseconds since the epoch
+    attempts: u32,
+    acked: bool,
+    dead: bool, // done with because it ran out of attempts, acked is set too
+}
+
+impl Channel {
+    fn reader(&self, consumer: &str) -> String {
+        super::reader(consumer, self.groups.get(consumer).map(String::as_str))
+    }
+
+    // Everyone a message has to reach, each group once
+    fn readers(&self) -> HashSet<String> {
+        self.permissions
+            .iter()
+            .map(|consumer| self.reader(consumer))
+            .collect()
+    }
+
+    fn delivery(&mut self, uuid: &str, reader: &str, consumer: &str) -> &mut Delivery {
+        self.deliveries
+            .entry(uuid.to_string())
+            .or_default()
+            .entry(reader.to_string())
+            .or_insert_with(|| Delivery {
+                member: consumer.to_string(),
+                visible_at: 0,
+                attempts: 0,
+                acked: false,
+                dead: false,
+            })
+    }
+
+    // The reader ran out of attempts on it, it counts as done like an ack
+    fn bury(&mut self, uuid: &str, reader: &str, consumer: &str) {
+        let delivery: &mut Delivery = self.delivery(uuid, reader, consumer);
+        delivery.acked = true;
+        delivery.dead = true;
+        self.settle(uuid);
+    }
+
+    // Kept until every reader is done with it, the deliveries a while longer
+    fn settle(&mut self, uuid: &str) {
+        let readers: HashSet<String> = self.readers();
+        let done: bool = match self.deliveries.get(uuid) {
+            Some(handed) => readers
+                .iter()
+                .all(|reader| handed.get(reader).is_some_and(|delivery| delivery.acked)),
+            None => false,
+        };
+        if done {
+            self.messages.retain(|queued| queued.uuid != uuid);
+            self.buried.remove(uuid);
+
+            let now: i64 = super::now_millis();
+            let expired: Vec<String> = self
+                .settled
+                .iter()
+                .filter(|(_, until)| **until <= now)
+                .map(|(settled, _)| settled.clone())
+                .collect();
+            for settled in &expired {
+                self.settled.remove(settled);
+                self.deliveries.remove(settled);
+            }
+            self.settled
+                .insert(uuid.to_string(), super::lease_end(super::ack_retention()));
+        }
+    }
+
+    // The message if it's still waiting for the reader, or why there's nothing left
+    // to do with it. Only a message handed to the reader counts, and while a lease on
+    // it runs only the member holding it can answer for the group. The reader's ack
+    // outlives the message, see settle.
+    fn waiting(&self, uuid: &str, reader: &str, consumer: &str) -> Result<Message> {
+        let delivery: Option<&Delivery> =
+            self.deliveries.get(uuid).and_then(|handed| handed.get(reader));
+        if delivery.is_some_and(|delivery| delivery.acked) {
+            return Err(ServerError::AlreadyAcked(uuid.to_string()).into());
+        }
+
+        let message: &Message = self
+            .messages
+            .iter()
+            .find(|queued| queued.uuid == uuid)
+            .ok_or_else(|| ServerError::UnknownMessage(uuid.to_string()))?;
+        match delivery {
+            Some(delivery)
+                if delivery.member != consumer && delivery.visible_at > super::now_millis() =>
+            {
+                Err(ServerError::UnknownMessage(uuid.to_string()).into())
+            }
+            Some(_) => Ok(message.clone()),
+            None => Err(ServerError::UnknownMessage(uuid.to_string()).into()),
+        }
+    }
+}
+
+impl MemoryStorage {
+    fn channels(&self) -> MutexGuard<'_, HashMap<String, Channel>> {
+        self.channels.lock().expect("Memory storage lock poisoned")
+    }
+}
+
+fn find<'a>(
+    channels: &'a mut HashMap<String, Channel>,
+    channel: &ChannelName,
+) -> Result<&'a mut Channel> {
+    channels
+        .get_mut(channel.as_str())
+        .ok_or_else(|| ServerError::ChannelNotFound(channel.to_string()).into())
+}
+
+// Copies of messages a reader of origin gave up on, a message several readers gave
+// up on is only there once
+fn dead_letter(
+    channels: &mut HashMap<String, Channel>,
+    target: &ChannelName,
+    origin: &ChannelName,
+    buried: Vec<Message>,
+) {
+    if let Some(dead_letter) = channels.get_mut(target.as_str()) {
+        for message in buried {
+            dead_letter
+                .buried
+                .entry(message.uuid.clone())
+                .or_default()
+                .insert(origin.as_str().to_string());
+            if !dead_letter.messages.iter().any(|queued| queued.uuid == message.uuid) {
+                dead_letter.messages.push_back(message);
+            }
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn create_channel(&self, channel: &ChannelName) -> Result<()> {
+        let mut channels = self.channels();
+        match channels.contains_key(channel.as_str()) {
+            true => Err(ServerError::ChannelExists(channel.to_string()).into()),
+            false => {
+                channels.insert(channel.as_str().to_string(), Channel::default());
+                Ok(())
+            }
+        }
+    }
+
+    fn delete_channel(&self, channel: &ChannelName) -> Result<()> {
+        let mut channels = self.channels();
+        if channels.remove(channel.as_str()).is_none() {
+            return Err(ServerError::ChannelNotFound(channel.to_string()).into());
+        }
+        for other in channels.values_mut() {
+            let unhook: bool = matches!(
+                &other.dead_letter,
+                Some((target, _)) if target.as_str() == channel.as_str()
+            );
+            if unhook {
+                other.dead_letter = None;
+            }
+            for origins in other.buried.values_mut() {
+                origins.remove(channel.as_str());
+            }
+            other.buried.retain(|_, origins| !origins.is_empty());
+        }
+        Ok(())
+    }
+
+    fn set_dead_letter(
+        &self,
+        channel: &ChannelName,
+        dead_letter: Option<(&ChannelName, u32)>,
+    ) -> Result<()> {
+        let mut channels = self.channels();
+        if let Some((target, _)) = dead_letter {
+            if !channels.contains_key(target.as_str()) {
+                return Err(ServerError::ChannelNotFound(target.to_string()).into());
+            }
+        }
+        find(&mut channels, channel)?.dead_letter =
+            dead_letter.map(|(target, max_attempts)| (target.clone(), max_attempts));
+        Ok(())
+    }
+
+    fn register(&self, channel: &ChannelName, uuid: &str, group: Option<&str>) -> Result<()> {
+        let mut channels = self.channels();
+        let channel: &mut Channel = find(&mut channels, channel)?;
+
+        if !channel.permissions.insert(uuid.to_string()) {
+            return Err(anyhow!("{} is already registered", uuid));
+        }
+        if let Some(group) = group {
+            channel.groups.insert(uuid.to_string(), group.to_string());
+        }
+        Ok(())
+    }
+
+    fn check_permission(&self, channel: &ChannelName, uuid: &str) -> Result<bool> {
+        let channels = self.channels();
+        let channel: &Channel = channels
+            .get(channel.as_str())
+            .ok_or_else(|| ServerError::ChannelNotFound(channel.to_string()))?;
+
+        Ok(channel.permissions.contains(uuid))
+    }
+
+    fn enqueue(&self, channel: &ChannelName, message: &Message) -> Result<()> {
+        let mut channels = self.channels();
+        let channel: &mut Channel = find(&mut channels, channel)?;
+
+        // The uuid is the primary key in the other backends
+        if channel.messages.iter().any(|queued| queued.uuid == message.uuid) {
+            return Err(anyhow!("Duplicate message {}", message.uuid));
+        }
+
+        channel.messages.push_back(message.clone());
+        Ok(())
+    }
+
+    fn fetch_many(
+        &self,
+        channel: &ChannelName,
+        consumer: &str,
+        limit: usize,
+        visibility: Duration,
+    ) -> Result<Fetched> {
+        let mut channels = self.channels();
+        let origin: ChannelName = channel.clone();
+        let channel: &mut Channel = find(&mut channels, channel)?;
+
+        let reader: String = channel.reader(consumer);
+        let now: i64 = super::now_millis();
+        let visible: Vec<Message> = channel
+            .messages
+            .iter()
+            .filter(|queued| {
+                match channel.deliveries.get(&queued.uuid).and_then(|d| d.get(&reader)) {
+                    Some(delivery) => !delivery.acked && delivery.visible_at <= now,
+                    None => true,
+                }
+            })
+            .cloned()
+            .collect();
+
+        let dead_letter: Option<(ChannelName, u32)> = channel.dead_letter.clone();
+        let visible_at: i64 = super::lease_end(visibility);
+        let (mut handed, mut buried): (Vec<Message>, Vec<Message>) = (Vec::new(), Vec::new());
+        for mut message in visible {
+            if handed.len() == limit {
+                break;
+            }
+            let delivery: &mut Delivery = channel.delivery(&message.uuid, &reader, consumer);
+            match &dead_letter {
+                Some((_, max_attempts)) if delivery.attempts >= *max_attempts => {
+                    buried.push(message)
+                }
+                _ => {
+                    delivery.member = consumer.to_string();
+                    delivery.visible_at = visible_at;
+                    delivery.attempts += 1;
+                    message.attempts = delivery.attempts;
+                    handed.push(message);
+                }
+            }
+        }
+        for message in &buried {
+            channel.bury(&message.uuid, &reader, consumer);
+        }
+
+        let dead_letter: Option<ChannelName> = match buried.is_empty() {
+            true => None,
+            false => dead_letter.map(|(target, _)| target),
+        };
+        if let Some(target) = &dead_letter {
+            self::dead_letter(&mut channels, target, &origin, buried);
+        }
+        Ok(Fetched {
+            messages: handed,
+            dead_letter,
+        })
+    }
+
+    fn leased(&self, channel: &ChannelName, consumer: &str) -> Result<usize> {
+        let channels = self.channels();
+        let channel: &Channel = channels
+            .get(channel.as_str())
+            .ok_or_else(|| ServerError::ChannelNotFound(channel.to_string()))?;
+
+        let (reader, now): (String, i64) = (channel.reader(consumer), super::now_millis());
+        let held: usize = channel
+            .messages
+            .iter()
+            .filter_map(|queued| channel.deliveries.get(&queued.uuid)?.get(&reader))
+            .filter(|delivery| {
+                !delivery.acked && delivery.member == consumer && delivery.visible_at > now
+            })
+            .count();
+        Ok(held)
+    }
+
+    fn acknowledge(&self, channel: &ChannelName, consumer: &str, uuid: &str) -> Result<()> {
+        let mut channels = self.channels();
+        let channel: &mut Channel = find(&mut channels, channel)?;
+
+        let reader: String = channel.reader(consumer);
+        channel.waiting(uuid, &reader, consumer)?;
+        channel.delivery(uuid, &reader, consumer).acked = true;
+        channel.settle(uuid);
+        Ok(())
+    }
+
+    fn reject(
+        &self,
+        channel: &ChannelName,
+        consumer: &str,
+        uuid: &str,
+        delay: Duration,
+    ) -> Result<Option<ChannelName>> {
+        let mut channels = self.channels();
+        let origin: ChannelName = channel.clone();
+        let channel: &mut Channel = find(&mut channels, channel)?;
+
+        let reader: String = channel.reader(consumer);
+        let message: Message = channel.waiting(uuid, &reader, consumer)?;
+        let dead_letter: Option<(ChannelName, u32)> = channel.dead_letter.clone();
+        let delivery: &mut Delivery = channel.delivery(uuid, &reader, consumer);
+        match dead_letter {
+            Some((target, max_attempts)) if delivery.attempts >= max_attempts => {
+                channel.bury(uuid, &reader, consumer);
+                self::dead_letter(&mut channels, &target, &origin, vec![message]);
+                Ok(Some(target))
+            }
+            _ => {
+                delivery.member = consumer.to_string();
+                delivery.visible_at = super::lease_end(delay);
+                Ok(None)
+            }
+        }
+    }
+
+    fn dead_letter(&self, channel: &ChannelName) -> Result<Option<(ChannelName, u32)>> {
+        Ok(find(&mut self.channels(), channel)?.dead_letter.clone())
+    }
+
+    fn replay(&self, channel: &ChannelName, dead_letter: &ChannelName) -> Result<usize> {
+        let mut channels = self.channels();
+        find(&mut channels, channel)?;
+
+        // Only what this channel buried, a copy other channels buried too stays for them
+        let mut replayed: Vec<Message> = Vec::new();
+        if let Some(target) = channels.get_mut(dead_letter.as_str()) {
+            let mut moved: Vec<String> = Vec::new();
+            for message in &target.messages {
+                if let Some(origins) = target.buried.get_mut(&message.uuid) {
+                    if origins.remove(channel.as_str()) {
+                        replayed.push(message.clone());
+                        if origins.is_empty() {
+                            moved.push(message.uuid.clone());
+                        }
+                    }
+                }
+            }
+            for uuid in &moved {
+                target.buried.remove(uuid);
+                target.deliveries.remove(uuid);
+                target.settled.remove(uuid);
+            }
+            target.messages.retain(|queued| !moved.contains(&queued.uuid));
+        }
+
+        // Readers that gave up on a message get it again, the others keep their acks
+        let origin: &mut Channel = find(&mut channels, channel)?;
+        for message in &replayed {
+            if let Some(handed) = origin.deliveries.get_mut(&message.uuid) {
+                handed.retain(|_, delivery| !delivery.dead);
+            }
+            // Removed already, its deliveries are kept again for as long as it's queued
+            if !origin.messages.iter().any(|queued| queued.uuid == message.uuid) {
+                origin.settled.remove(&message.uuid);
+                origin.messages.push_back(message.clone());
+            }
+        }
+        Ok(replayed.len())
+    }
+}
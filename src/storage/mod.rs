@@ -0,0 +1,242 @@
+pub mod memory;
+pub mod mysql;
+pub mod sqlite;
+
+use {
+    crate::{
+        config::{self, Backend},
+        error::{ServerError, ServerResult},
+        skel::{ChannelName, Message},
+    },
+    anyhow::{anyhow, Result},
+    std::{
+        sync::{Arc, OnceLock},
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+    tokio::task,
+};
+
+// Everything the server persists goes through one of these. Implementations are
+// blocking, handlers reach them through with_storage. Channel names arrive already
+// validated, every other value has to be bound as a query parameter.
+pub trait Storage: Send + Sync {
+    // Channels. Deleting one also unhooks the channels dead-lettering into it.
+    fn create_channel(&self, channel: &ChannelName) -> Result<()>;
+    fn delete_channel(&self, channel: &ChannelName) -> Result<()>;
+    // Where a message goes once it was handed out max_attempts times to a reader
+    // without an ack. None hands it out again for as long as it takes.
+    fn set_dead_letter(
+        &self,
+        channel: &ChannelName,
+        dead_letter: Option<(&ChannelName, u32)>,
+    ) -> Result<()>;
+
+    // Registration and permissions, members of a group share one stream
+    fn register(&self, channel: &ChannelName, uuid: &str, group: Option<&str>) -> Result<()>;
+    fn check_permission(&self, channel: &ChannelName, uuid: &str) -> Result<bool>;
+
+    // Messages. Every reader of a channel gets every message: each client registered
+    // on its own, and each group once, handed to one of its members. A message is
+    // removed once every reader has acked it.
+    fn enqueue(&self, channel: &ChannelName, message: &Message) -> Result<()>;
+    // Up to limit messages for the consumer, oldest first, that its reader hasn't
+    // acked and nobody holds a lease on. Each one is leased to the consumer: hidden
+    // from the reader for `visibility`, then handed out again unless it was acked.
+    // Every lease counts as a delivery attempt. Messages that ran out of attempts
+    // are moved to the dead-letter channel instead of being handed out.
+    fn fetch_many(
+        &self,
+        channel: &ChannelName,
+        consumer: &str,
+        limit: usize,
+        visibility: Duration,
+    ) -> Result<Fetched>;
+    // How many unacked messages the consumer holds a lease on that hasn't run out
+    fn leased(&self, channel: &ChannelName, consumer: &str) -> Result<usize>;
+    // Messages are named by their uuid. One that isn't waiting on the channel or was
+    // never handed to the consumer's reader is an UnknownMessage, and so is one
+    // leased to another member of its group. One the reader is done with is
+    // AlreadyAcked, also once the message is gone until its ack_retention runs out.
+    fn acknowledge(&self, channel: &ChannelName, consumer: &str, uuid: &str) -> Result<()>;
+    // Hands a message back before its lease runs out, it's hidden for delay and then
+    // handed out again. One that ran out of attempts goes to the dead-letter channel,
+    // which is returned.
+    fn reject(
+        &self,
+        channel: &ChannelName,
+        consumer: &str,
+        uuid: &str,
+        delay: Duration,
+    ) -> Result<Option<ChannelName>>;
+    // The channel's dead-letter channel and how many attempts a message gets first
+    fn dead_letter(&self, channel: &ChannelName) -> Result<Option<(ChannelName, u32)>>;
+    // Moves the messages the channel buried in dead_letter back into it, each message
+    // going to the readers that gave up on it. What other channels buried there stays.
+    // Returns how many were moved.
+    fn replay(&self, channel: &ChannelName, dead_letter: &ChannelName) -> Result<usize>;
+}
+
+// What a fetch handed out, and the dead-letter channel when it moved any there
+pub struct Fetched {
+    pub messages: Vec<Message>,
+    pub dead_letter: Option<ChannelName>,
+}
+
+// The sql backends record deliveries in ironpulse_deliveries, a row per reader and
+// message saying which consumer was handed it, until when it's hidden, how many
+// times it was handed out and whether it was acked (1) or dead-lettered (2), group
+// membership in ironpulse_members, dead-letter settings in ironpulse_dead_letters and
+// which channels buried a dead letter in ironpulse_buried. All four are shared by
+// every channel and created at startup. Channel names can't contain an underscore,
+// so they never collide. Once every reader is done with a message its deliveries
+// stay behind until the ack retention runs out, visible_at saying when, so acking it
+// again is still recognised.
+pub fn reader(consumer: &str, group: Option<&str>) -> String {
+    match group {
+        Some(group) => format!("group:{}", group),
+        None => consumer.to_string(),
+    }
+}
+
+// Lease deadlines are kept as milliseconds since the epoch, so they survive a restart
+pub fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as i64)
+}
+
+pub fn lease_end(visibility: Duration) -> i64 {
+    now_millis().saturating_add(visibility.as_millis() as i64)
+}
+
+// How long a message handed out by a fetch stays hidden, see limits.visibility_timeout
+pub fn visibility() -> Duration {
+    Duration::from_millis(config::get().limits.visibility_timeout)
+}
+
+// How long the deliveries of a removed message are kept, see limits.ack_retention
+pub fn ack_retention() -> Duration {
+    Duration::from_millis(config::get().limits.ack_retention)
+}
+
+static STORAGE: OnceLock<Arc<dyn Storage>> = OnceLock::new();
+
+// Opens the backend picked in the config, called once at startup
+pub fn init() -> Result<()> {
+    let settings = &config::get().storage;
+    let storage: Arc<dyn Storage> = match settings.backend {
+        Backend::Mysql => Arc::new(mysql::MysqlStorage::connect()?),
+        Backend::Sqlite => Arc::new(sqlite::SqliteStorage::open(&settings.path)?),
+        Backend::Memory => Arc::new(memory::MemoryStorage::default()),
+    };
+
+    let _ = STORAGE.set(storage);
+    Ok(())
+}
+
+// Storage calls may block, so they run on tokio's blocking pool instead of stalling
+// the threads that serve every other connection
+pub async fn with_storage<F, T>(job: F) -> ServerResult<T>
+where
+    F: FnOnce(&dyn Storage) -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let storage: Arc<dyn Storage> = match STORAGE.get() {
+        Some(storage) => Arc::clone(storage),
+        None => return Err(ServerError::Storage(anyhow!("Storage used before init"))),
+    };
+
+    match task::spawn_blocking(move || job(storage.as_ref())).await {
+        Ok(result) => Ok(result?),
+        Err(e) => Err(ServerError::Storage(anyhow!("Storage task failed: {}", e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::testing};
+
+    const WORKER: &str = "worker-01";
+
+    fn message(uuid: &str) -> Message {
+        Message {
+            uuid: uuid.to_string(),
+            message_type: "text".to_string(),
+            message: uuid.to_string(),
+            hash: uuid.to_string(),
+            attempts: 0,
+        }
+    }
+
+    fn uuids(fetched: &Fetched) -> Vec<&str> {
+        fetched.messages.iter().map(|message| message.uuid.as_str()).collect()
+    }
+
+    // Messages out of attempts are buried while fetching, and the fetch reads on for
+    // the ones behind them
+    fn exhausted_candidates_make_room(storage: &dyn Storage) {
+        testing::setup();
+        let channel: ChannelName = ChannelName::parse("mail").unwrap();
+        let dead: ChannelName = ChannelName::parse("dead").unwrap();
+        storage.create_channel(&channel).unwrap();
+        storage.create_channel(&dead).unwrap();
+        storage.set_dead_letter(&channel, Some((&dead, 1))).unwrap();
+        storage.register(&channel, WORKER, None).unwrap();
+        for uuid in ["first", "second", "third"] {
+            storage.enqueue(&channel, &message(uuid)).unwrap();
+        }
+
+        let fetched: Fetched = storage.fetch_many(&channel, WORKER, 2, Duration::ZERO).unwrap();
+        assert_eq!(uuids(&fetched), ["first", "second"]);
+        assert!(fetched.dead_letter.is_none());
+
+        let fetched: Fetched = storage.fetch_many(&channel, WORKER, 2, Duration::ZERO).unwrap();
+        assert_eq!(uuids(&fetched), ["third"]);
+        assert_eq!(fetched.dead_letter.as_ref().map(ChannelName::as_str), Some("dead"));
+
+        let fetched: Fetched = storage.fetch_many(&channel, WORKER, 2, Duration::ZERO).unwrap();
+        assert!(fetched.messages.is_empty());
+        assert_eq!(fetched.dead_letter.as_ref().map(ChannelName::as_str), Some("dead"));
+    }
+
+    // The ack outlives the message it removed, so a retried ack isn't taken for an
+    // unknown message
+    fn acking_twice_is_already_acked(storage: &dyn Storage) {
+        testing::setup();
+        let channel: ChannelName = ChannelName::parse("mail").unwrap();
+        storage.create_channel(&channel).unwrap();
+        storage.register(&channel, WORKER, None).unwrap();
+        storage.enqueue(&channel, &message("only")).unwrap();
+
+        let fetched: Fetched = storage.fetch_many(&channel, WORKER, 1, Duration::ZERO).unwrap();
+        assert_eq!(uuids(&fetched), ["only"]);
+        storage.acknowledge(&channel, WORKER, "only").unwrap();
+
+        let again: anyhow::Error = storage.acknowledge(&channel, WORKER, "only").unwrap_err();
+        assert!(matches!(again.downcast_ref(), Some(ServerError::AlreadyAcked(_))));
+        let unknown: anyhow::Error = storage.acknowledge(&channel, WORKER, "never").unwrap_err();
+        assert!(matches!(unknown.downcast_ref(), Some(ServerError::UnknownMessage(_))));
+        let fetched: Fetched = storage.fetch_many(&channel, WORKER, 1, Duration::ZERO).unwrap();
+        assert!(fetched.messages.is_empty());
+    }
+
+    #[test]
+    fn memory_buries_exhausted_candidates() {
+        exhausted_candidates_make_room(&memory::MemoryStorage::default());
+    }
+
+    #[test]
+    fn sqlite_buries_exhausted_candidates() {
+        exhausted_candidates_make_room(&sqlite::SqliteStorage::open(":memory:").unwrap());
+    }
+
+    #[test]
+    fn memory_remembers_acks() {
+        acking_twice_is_already_acked(&memory::MemoryStorage::default());
+    }
+
+    #[test]
+    fn sqlite_remembers_acks() {
+        acking_twice_is_already_acked(&sqlite::SqliteStorage::open(":memory:").unwrap());
+    }
+}
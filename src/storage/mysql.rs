@@ -0,0 +1,839 @@
+use {
+    super::{Fetched, Storage},
+    crate::{
+        config,
+        database::create_pool,
+        error::ServerError,
+        skel::{ChannelName, Message},
+        PROG,
+    },
+    anyhow::{anyhow, Result},
+    logging::append_log,
+    mysql::{prelude::Queryable, Pool, PooledConn, TxOpts},
+    std::time::Duration,
+};
+
+// The original backend, one message table and one permission table per channel,
+// plus the tables every channel shares, see ironpulse_deliveries in storage/mod.rs
+pub struct MysqlStorage {
+    pool: Pool,
+}
+
+// uuid, message_type, message, hash and how many times the reader was handed it
+type MessageRow = (String, String, String, String, u32);
+
+impl MysqlStorage {
+    // Reads the credentials from recs and opens the pool
+    pub fn connect() -> Result<Self> {
+        let storage = MysqlStorage {
+            pool: create_pool()?,
+        };
+
+        // Created on first start, so existing channels keep working as they are
+        let create_deliveries: String = format!(
+            r"CREATE TABLE IF NOT EXISTS {} (
+                channel VARCHAR(48) NOT NULL,
+                reader VARCHAR(255) NOT NULL,
+                message_uuid VARCHAR(380) NOT NULL,
+                member VARCHAR(255) NOT NULL,
+                visible_at BIGINT NOT NULL DEFAULT 0,
+                attempts INT UNSIGNED NOT NULL DEFAULT 0,
+                acked BOOLEAN NOT NULL DEFAULT 0,
+                PRIMARY KEY (channel, reader, message_uuid)
+            )",
+            deliveries_table()
+        );
+        let create_members: String = format!(
+            r"CREATE TABLE IF NOT EXISTS {} (
+                channel VARCHAR(48) NOT NULL,
+                consumer VARCHAR(255) NOT NULL,
+                group_name VARCHAR(48) NOT NULL,
+                PRIMARY KEY (channel, consumer)
+            )",
+            members_table()
+        );
+
+        let create_dead_letters: String = format!(
+            r"CREATE TABLE IF NOT EXISTS {} (
+                channel VARCHAR(48) NOT NULL,
+                dead_letter VARCHAR(48) NOT NULL,
+                max_attempts INT UNSIGNED NOT NULL,
+                PRIMARY KEY (channel)
+            )",
+            dead_letters_table()
+        );
+        let create_buried: String = format!(
+            r"CREATE TABLE IF NOT EXISTS {} (
+                dead_letter VARCHAR(48) NOT NULL,
+                message_uuid VARCHAR(380) NOT NULL,
+                origin VARCHAR(48) NOT NULL,
+                PRIMARY KEY (dead_letter, message_uuid, origin)
+            )",
+            buried_table()
+        );
+
+        let mut conn = storage.conn()?;
+        conn.query_drop(create_deliveries)
+            .and_then(|_| conn.query_drop(create_members))
+            .and_then(|_| conn.query_drop(create_dead_letters))
+            .and_then(|_| conn.query_drop(create_buried))
+            .map_err(|e| anyhow!("Couldn't create the delivery tables: {}", e))?;
+
+        // Message tables from before seq get it now. The rows already queued are
+        // numbered in uuid order, nothing recorded when they were stored.
+        let schema: &str = &config::get().database.schema;
+        let unordered_query: &str = r"SELECT table_name FROM information_schema.columns
+            WHERE table_schema = ? AND column_name = 'processed'
+                AND table_name NOT IN (SELECT table_name FROM information_schema.columns
+                    WHERE table_schema = ? AND column_name = 'seq')";
+        let unordered: Vec<String> = conn
+            .exec(unordered_query, (schema, schema))
+            .map_err(|e| anyhow!("Couldn't look for message tables without seq: {}", e))?;
+        for table in unordered {
+            let add_seq: String = format!(
+                r"ALTER TABLE `{}`.`{}`
+                ADD COLUMN seq BIGINT UNSIGNED NOT NULL AUTO_INCREMENT UNIQUE",
+                schema, table
+            );
+            conn.query_drop(add_seq)
+                .map_err(|e| anyhow!("Couldn't add seq to {}: {}", table, e))?;
+            append_log(PROG, &format!("Added seq to the message table {}", table));
+        }
+
+        // Same for hash. Their messages were named by their hash, so the id they were
+        // handed out with stays the same.
+        let unhashed_query: &str = r"SELECT table_name FROM information_schema.columns
+            WHERE table_schema = ? AND column_name = 'processed'
+                AND table_name NOT IN (SELECT table_name FROM information_schema.columns
+                    WHERE table_schema = ? AND column_name = 'hash')";
+        let unhashed: Vec<String> = conn
+            .exec(unhashed_query, (schema, schema))
+            .map_err(|e| anyhow!("Couldn't look for message tables without hash: {}", e))?;
+        for table in unhashed {
+            let add_hash: String = format!(
+                "ALTER TABLE `{}`.`{}` ADD COLUMN hash VARCHAR(380) NOT NULL DEFAULT ''",
+                schema, table
+            );
+            let fill_hash: String = format!("UPDATE `{}`.`{}` SET hash = uuid", schema, table);
+            conn.query_drop(add_hash)
+                .and_then(|_| conn.query_drop(fill_hash))
+                .map_err(|e| anyhow!("Couldn't add hash to {}: {}", table, e))?;
+            append_log(PROG, &format!("Added hash to the message table {}", table));
+        }
+        Ok(storage)
+    }
+
+    fn conn(&self) -> Result<PooledConn> {
+        self.pool
+            .get_conn()
+            .map_err(|e| ServerError::Unavailable(format!("DATABASE UNAVAILABLE: {}", e)).into())
+    }
+}
+
+// Turns the server errors clients can act on into their ServerError, the rest
+// stay plain storage failures
+fn failure(e: mysql::Error, channel: &ChannelName) -> anyhow::Error {
+    match &e {
+        mysql::Error::MySqlError(error) => match error.code {
+            // unknown table
+            1051 | 1146 => ServerError::ChannelNotFound(channel.to_string()).into(),
+            // table already exists
+            1050 => ServerError::ChannelExists(channel.to_string()).into(),
+            // data too long for its column
+            1406 => ServerError::TooLarge(format!("{} on {}", error.message, channel)).into(),
+            _ => e.into(),
+        },
+        mysql::Error::IoError(_) | mysql::Error::DriverError(_) => {
+            ServerError::Unavailable(format!("{}", e)).into()
+        }
+        _ => e.into(),
+    }
+}
+
+// Schema qualified table names, the schema is validated when the config loads
+fn message_table(channel: &ChannelName) -> String {
+    format!("`{}`.{}", config::get().database.schema, channel.message_table())
+}
+
+fn permission_table(channel: &ChannelName) -> String {
+    format!("`{}`.{}", config::get().database.schema, channel.permission_table())
+}
+
+fn deliveries_table() -> String {
+    format!("`{}`.ironpulse_deliveries", config::get().database.schema)
+}
+
+fn members_table() -> String {
+    format!("`{}`.ironpulse_members", config::get().database.schema)
+}
+
+fn dead_letters_table() -> String {
+    format!("`{}`.ironpulse_dead_letters", config::get().database.schema)
+}
+
+fn buried_table() -> String {
+    format!("`{}`.ironpulse_buried", config::get().database.schema)
+}
+
+// Who a delivery is recorded for, the consumer's group if it's in one
+fn reader<Q: Queryable>(conn: &mut Q, channel: &ChannelName, consumer: &str) -> Result<String> {
+    let group_query: String = format!(
+        "SELECT group_name FROM {} WHERE channel = ? AND consumer = ?",
+        members_table()
+    );
+    let group: Option<String> = conn
+        .exec_first(group_query, (channel.as_str(), consumer))
+        .map_err(|e| failure(e, channel))?;
+    Ok(super::reader(consumer, group.as_deref()))
+}
+
+impl Storage for MysqlStorage {
+    fn create_channel(&self, channel: &ChannelName) -> Result<()> {
+        let mut conn = self.conn()?;
+
+        let create_message: String = format!(
+            r"CREATE TABLE {} (
+                uuid VARCHAR(380) NOT NULL,
+                message_type VARCHAR(1024) NOT NULL,
+                message VARCHAR(4096) NOT NULL,
+                hash VARCHAR(380) NOT NULL,
+                processed BOOLEAN not null DEFAULT 0,
+                seq BIGINT UNSIGNED NOT NULL AUTO_INCREMENT UNIQUE,
+                PRIMARY KEY (uuid)
+            )",
+            message_table(channel)
+        );
+        let create_permission: String = format!(
+            r"CREATE TABLE {} (
+                uuid VARCHAR(380) NOT NULL,
+                PRIMARY KEY (uuid)
+            )",
+            permission_table(channel)
+        );
+
+        let magic = (
+            conn.query_drop(create_message),
+            conn.query_drop(create_permission),
+        );
+
+        match magic {
+            (Ok(_), Ok(_)) => {
+                append_log(PROG, &format!("Tables created for {}", channel));
+                Ok(())
+            }
+            (Ok(_), Err(e)) => Err(anyhow!("Unable to create permission table: {}", e)),
+            (Err(e), Ok(_)) => Err(anyhow!("Unable to create message table: {}", e)),
+            (Err(e1), Err(e2)) => {
+                append_log(PROG, &format!("Permission table not created: {}", e2));
+                Err(failure(e1, channel))
+            }
+        }
+    }
+
+    fn delete_channel(&self, channel: &ChannelName) -> Result<()> {
+        let mut conn = self.conn()?;
+
+        let drop_message: String = format!("DROP TABLE {}", message_table(channel));
+        let drop_permission: String = format!("DROP TABLE {}", permission_table(channel));
+
+        let drop_tuple = (
+            conn.query_drop(drop_message),
+            conn.query_drop(drop_permission),
+        );
+
+        for shared in [deliveries_table(), members_table()] {
+            let forget: String = format!("DELETE FROM {} WHERE channel = ?", shared);
+            if let Err(e) = conn.exec_drop(forget, (channel.as_str(),)) {
+                append_log(PROG, &format!("{} for {} not dropped: {}", shared, channel, e));
+            }
+        }
+        let unhook: String = format!(
+            "DELETE FROM {} WHERE channel = ? OR dead_letter = ?",
+            dead_letters_table()
+        );
+        if let Err(e) = conn.exec_drop(unhook, (channel.as_str(), channel.as_str())) {
+            append_log(PROG, &format!("Dead letters of {} not dropped: {}", channel, e));
+        }
+        let unbury: String = format!(
+            "DELETE FROM {} WHERE dead_letter = ? OR origin = ?",
+            buried_table()
+        );
+        if let Err(e) = conn.exec_drop(unbury, (channel.as_str(), channel.as_str())) {
+            append_log(PROG, &format!("Buried messages of {} not dropped: {}", channel, e));
+        }
+
+        match drop_tuple {
+            (Ok(_), Ok(_)) => Ok(()),
+            (Ok(_), Err(e)) | (Err(e), Ok(_)) => Err(anyhow!("Partially dropped: {}", e)),
+            (Err(e1), Err(e2)) => {
+                append_log(PROG, &format!("Permission table not dropped: {}", e2));
+                Err(failure(e1, channel))
+            }
+        }
+    }
+
+    fn set_dead_letter(
+        &self,
+        channel: &ChannelName,
+        dead_letter: Option<(&ChannelName, u32)>,
+    ) -> Result<()> {
+        let mut conn = self.conn()?;
+        match dead_letter {
+            Some((target, max_attempts)) => {
+                let set_query: String = format!(
+                    r"INSERT INTO {} (channel, dead_letter, max_attempts) VALUES (?, ?, ?)
+                    ON DUPLICATE KEY UPDATE
+                        dead_letter = VALUES(dead_letter), max_attempts = VALUES(max_attempts)",
+                    dead_letters_table()
+                );
+                conn.exec_drop(set_query, (channel.as_str(), target.as_str(), max_attempts))
+            }
+            None => {
+                let unset_query: String =
+                    format!("DELETE FROM {} WHERE channel = ?", dead_letters_table());
+                conn.exec_drop(unset_query, (channel.as_str(),))
+            }
+        }
+        .map_err(|e| failure(e, channel))?;
+        Ok(())
+    }
+
+    fn register(&self, channel: &ChannelName, uuid: &str, group: Option<&str>) -> Result<()> {
+        let register_query: String =
+            format!(r"INSERT INTO {} (uuid) VALUES (?)", permission_table(channel));
+        let member_query: String = format!(
+            r"INSERT INTO {} (channel, consumer, group_name) VALUES (?, ?, ?)",
+            members_table()
+        );
+
+        let mut conn = self.conn()?;
+        let mut transaction = conn
+            .start_transaction(TxOpts::default())
+            .map_err(|e| failure(e, channel))?;
+        transaction
+            .exec_drop(register_query, (uuid,))
+            .map_err(|e| failure(e, channel))?;
+        if let Some(group) = group {
+            transaction
+                .exec_drop(member_query, (channel.as_str(), uuid, group))
+                .map_err(|e| failure(e, channel))?;
+        }
+        transaction.commit().map_err(|e| failure(e, channel))?;
+        Ok(())
+    }
+
+    fn check_permission(&self, channel: &ChannelName, uuid: &str) -> Result<bool> {
+        let mut conn = self.conn()?;
+
+        let perm_query: String = format!(
+            "SELECT COUNT(*) FROM {} WHERE uuid = ?",
+            permission_table(channel)
+        );
+        let count: i32 = conn
+            .exec_first(perm_query, (uuid,))
+            .map_err(|e| failure(e, channel))?
+            .unwrap_or(0);
+
+        // This is where the delivered messages get deleted
+        let maintence_query: String =
+            format!("DELETE FROM {} WHERE processed = '1'", message_table(channel));
+        match conn.query_drop(maintence_query) {
+            Ok(_) => append_log(PROG, "Maintence drops"),
+            Err(e) => append_log(PROG, &format!("Maintence Drops Failed: {}", e)),
+        };
+        // And the acks kept for them once their retention ran out
+        let expired_query: String = format!(
+            r"DELETE FROM {} WHERE channel = ? AND acked != 0 AND visible_at <= ?
+                AND message_uuid NOT IN (SELECT uuid FROM {} WHERE processed = '0')",
+            deliveries_table(),
+            message_table(channel)
+        );
+        if let Err(e) = conn.exec_drop(expired_query, (channel.as_str(), super::now_millis())) {
+            append_log(PROG, &format!("Maintence Drops Failed: {}", e));
+        }
+
+        Ok(count != 0)
+    }
+
+    fn enqueue(&self, channel: &ChannelName, message: &Message) -> Result<()> {
+        let commit_query: String = format!(
+            r"INSERT INTO {} (uuid, message_type, message, hash) VALUES (?, ?, ?, ?)",
+            message_table(channel)
+        );
+
+        self.conn()?
+            .exec_drop(
+                commit_query,
+                (&message.uuid, &message.message_type, &message.message, &message.hash),
+            )
+            .map_err(|e| failure(e, channel))?;
+        Ok(())
+    }
+
+    fn fetch_many(
+        &self,
+        channel: &ChannelName,
+        consumer: &str,
+        limit: usize,
+        visibility: Duration,
+    ) -> Result<Fetched> {
+        // Never handed to the reader, or handed out and not acked before the lease ran
+        // out. Oldest first, the uuid says nothing about when a message came in. A
+        // locking read sees what other members committed since this transaction began,
+        // and skips deliveries an ack or another fetch is deciding about right now.
+        let check_query = |limit: usize| -> String {
+            format!(
+                r"SELECT uuid, message_type, message, hash, COALESCE(handed.attempts, 0)
+                FROM {} AS queued
+                LEFT JOIN {} AS handed
+                    ON handed.channel = ? AND handed.reader = ?
+                        AND handed.message_uuid = queued.uuid
+                WHERE queued.processed = '0' AND (handed.message_uuid IS NULL
+                    OR (handed.acked = 0 AND handed.visible_at <= ?))
+                ORDER BY queued.seq
+                LIMIT {}
+                FOR UPDATE OF handed SKIP LOCKED",
+                message_table(channel),
+                deliveries_table(),
+                limit
+            )
+        };
+        // Only takes a lease that is still free, so the row decides who gets the message
+        let retake_query: String = format!(
+            r"UPDATE {} SET member = ?, visible_at = ?, attempts = attempts + 1
+            WHERE channel = ? AND reader = ? AND message_uuid = ?
+                AND acked = 0 AND visible_at <= ?",
+            deliveries_table()
+        );
+        let hand_query: String = format!(
+            r"INSERT IGNORE INTO {} (channel, reader, message_uuid, member, visible_at, attempts)
+            VALUES (?, ?, ?, ?, ?, 1)",
+            deliveries_table()
+        );
+        // Same for giving up on it, only one member buries it
+        let give_up_query: String = format!(
+            r"UPDATE {} SET member = ?, acked = 2
+            WHERE channel = ? AND reader = ? AND message_uuid = ?
+                AND acked = 0 AND visible_at <= ?",
+            deliveries_table()
+        );
+
+        let mut conn = self.conn()?;
+        let mut transaction = conn
+            .start_transaction(TxOpts::default())
+            .map_err(|e| failure(e, channel))?;
+        let reader: String = reader(&mut transaction, channel, consumer)?;
+        let dead_letter: Option<(ChannelName, u32)> = dead_letter(&mut transaction, channel)?;
+        let now: i64 = super::now_millis();
+        let visible_at: i64 = super::lease_end(visibility);
+
+        // Buried messages and ones another member took leave room, so read again until
+        // a read hands out everything it found
+        let (mut handed, mut buried): (Vec<Message>, usize) = (Vec::new(), 0);
+        loop {
+            let candidates: Vec<Message> = transaction
+                .exec_map(
+                    check_query(limit - handed.len()),
+                    (channel.as_str(), &reader, now),
+                    |(uuid, message_type, message, hash, attempts): MessageRow| Message {
+                        uuid,
+                        message_type,
+                        message,
+                        hash,
+                        attempts: attempts + 1,
+                    },
+                )
+                .map_err(|e| failure(e, channel))?;
+
+            // Another member of the group may have taken one since the read, whoever
+            // changes the row first has it and the others move on to the next one
+            let mut passed: bool = false;
+            for message in candidates {
+                match (&dead_letter, message.attempts) {
+                    (Some((target, max_attempts)), attempts) if attempts > *max_attempts => {
+                        transaction
+                            .exec_drop(
+                                &give_up_query,
+                                (consumer, channel.as_str(), &reader, &message.uuid, now),
+                            )
+                            .map_err(|e| failure(e, channel))?;
+                        if transaction.affected_rows() != 0 {
+                            bury(&mut transaction, channel, &message.uuid, target)?;
+                            (buried, passed) = (buried + 1, true);
+                        }
+                    }
+                    (_, 1) => {
+                        transaction
+                            .exec_drop(
+                                &hand_query,
+                                (channel.as_str(), &reader, &message.uuid, consumer, visible_at),
+                            )
+                            .map_err(|e| failure(e, channel))?;
+                        if transaction.affected_rows() != 0 {
+                            handed.push(message);
+                        } else {
+                            passed = true;
+                        }
+                    }
+                    _ => {
+                        let uuid: &str = &message.uuid;
+                        transaction
+                            .exec_drop(
+                                &retake_query,
+                                (consumer, visible_at, channel.as_str(), &reader, uuid, now),
+                            )
+                            .map_err(|e| failure(e, channel))?;
+                        if transaction.affected_rows() != 0 {
+                            handed.push(message);
+                        } else {
+                            passed = true;
+                        }
+                    }
+                }
+            }
+            if !passed || handed.len() == limit {
+                break;
+            }
+        }
+
+        transaction.commit().map_err(|e| failure(e, channel))?;
+        Ok(Fetched {
+            messages: handed,
+            dead_letter: dead_letter.filter(|_| buried > 0).map(|(target, _)| target),
+        })
+    }
+
+    fn leased(&self, channel: &ChannelName, consumer: &str) -> Result<usize> {
+        let leased_query: String = format!(
+            r"SELECT COUNT(*) FROM {} AS handed
+            JOIN {} AS queued ON queued.uuid = handed.message_uuid
+            WHERE handed.channel = ? AND handed.reader = ? AND handed.member = ?
+                AND handed.acked = 0 AND handed.visible_at > ? AND queued.processed = '0'",
+            deliveries_table(),
+            message_table(channel)
+        );
+
+        let mut conn = self.conn()?;
+        let reader: String = reader(&mut conn, channel, consumer)?;
+        let held: Option<u64> = conn
+            .exec_first(
+                leased_query,
+                (channel.as_str(), &reader, consumer, super::now_millis()),
+            )
+            .map_err(|e| failure(e, channel))?;
+        Ok(held.unwrap_or(0) as usize)
+    }
+
+    fn acknowledge(&self, channel: &ChannelName, consumer: &str, uuid: &str) -> Result<()> {
+        let record_query: String = format!(
+            r"INSERT INTO {} (channel, reader, message_uuid, member, acked) VALUES (?, ?, ?, ?, 1)
+            ON DUPLICATE KEY UPDATE acked = 1",
+            deliveries_table()
+        );
+
+        let mut conn = self.conn()?;
+        let mut transaction = conn
+            .start_transaction(TxOpts::default())
+            .map_err(|e| failure(e, channel))?;
+        let reader: String = reader(&mut transaction, channel, consumer)?;
+        waiting(&mut transaction, channel, &reader, consumer, uuid)?;
+
+        transaction
+            .exec_drop(record_query, (channel.as_str(), &reader, uuid, consumer))
+            .map_err(|e| failure(e, channel))?;
+        settle(&mut transaction, channel, uuid)?;
+
+        transaction.commit().map_err(|e| failure(e, channel))?;
+        Ok(())
+    }
+
+    fn reject(
+        &self,
+        channel: &ChannelName,
+        consumer: &str,
+        uuid: &str,
+        delay: Duration,
+    ) -> Result<Option<ChannelName>> {
+        let give_up_query: String = format!(
+            r"INSERT INTO {} (channel, reader, message_uuid, member, acked) VALUES (?, ?, ?, ?, 2)
+            ON DUPLICATE KEY UPDATE acked = 2",
+            deliveries_table()
+        );
+        let requeue_query: String = format!(
+            r"INSERT INTO {} (channel, reader, message_uuid, member, visible_at)
+            VALUES (?, ?, ?, ?, ?)
+            ON DUPLICATE KEY UPDATE member = VALUES(member), visible_at = VALUES(visible_at)",
+            deliveries_table()
+        );
+
+        let mut conn = self.conn()?;
+        let mut transaction = conn
+            .start_transaction(TxOpts::default())
+            .map_err(|e| failure(e, channel))?;
+        let reader: String = reader(&mut transaction, channel, consumer)?;
+        let attempts: u32 = waiting(&mut transaction, channel, &reader, consumer, uuid)?;
+
+        let buried: Option<ChannelName> = match dead_letter(&mut transaction, channel)? {
+            Some((target, max_attempts)) if attempts >= max_attempts => {
+                transaction
+                    .exec_drop(give_up_query, (channel.as_str(), &reader, uuid, consumer))
+                    .map_err(|e| failure(e, channel))?;
+                bury(&mut transaction, channel, uuid, &target)?;
+                Some(target)
+            }
+            _ => {
+                let visible_at: i64 = super::lease_end(delay);
+                transaction
+                    .exec_drop(
+                        requeue_query,
+                        (channel.as_str(), &reader, uuid, consumer, visible_at),
+                    )
+                    .map_err(|e| failure(e, channel))?;
+                None
+            }
+        };
+
+        transaction.commit().map_err(|e| failure(e, channel))?;
+        Ok(buried)
+    }
+
+    fn dead_letter(&self, channel: &ChannelName) -> Result<Option<(ChannelName, u32)>> {
+        let mut conn = self.conn()?;
+        dead_letter(&mut conn, channel)
+    }
+
+    fn replay(&self, channel: &ChannelName, target: &ChannelName) -> Result<usize> {
+        let mut conn = self.conn()?;
+        let mut transaction = conn
+            .start_transaction(TxOpts::default())
+            .map_err(|e| failure(e, channel))?;
+
+        // Only what this channel buried there
+        let copies_query: String = format!(
+            r"SELECT queued.uuid FROM {} AS queued
+            JOIN {} AS buried
+                ON buried.dead_letter = ? AND buried.message_uuid = queued.uuid
+            WHERE buried.origin = ? AND queued.processed = '0'
+            ORDER BY queued.seq",
+            message_table(target),
+            buried_table()
+        );
+        // Back in the channel, a copy still waiting there for maintenance is revived
+        let restore_query: String = format!(
+            r"INSERT INTO {} (uuid, message_type, message, hash)
+            SELECT uuid, message_type, message, hash FROM {} WHERE uuid = ?
+            ON DUPLICATE KEY UPDATE processed = '0'",
+            message_table(channel),
+            message_table(target)
+        );
+        // Readers that gave up on it get it again, the others keep their acks
+        let revive_query: String = format!(
+            "DELETE FROM {} WHERE channel = ? AND message_uuid = ? AND acked = 2",
+            deliveries_table()
+        );
+        let unbury_query: String = format!(
+            "DELETE FROM {} WHERE dead_letter = ? AND message_uuid = ? AND origin = ?",
+            buried_table()
+        );
+        let buried_query: String = format!(
+            "SELECT COUNT(*) FROM {} WHERE dead_letter = ? AND message_uuid = ?",
+            buried_table()
+        );
+        let remove_query: String =
+            format!("DELETE FROM {} WHERE uuid = ?", message_table(target));
+        let forget_query: String = format!(
+            "DELETE FROM {} WHERE channel = ? AND message_uuid = ?",
+            deliveries_table()
+        );
+
+        let copies: Vec<String> = transaction
+            .exec(copies_query, (target.as_str(), channel.as_str()))
+            .map_err(|e| failure(e, target))?;
+        for uuid in &copies {
+            transaction
+                .exec_drop(&restore_query, (uuid,))
+                .map_err(|e| failure(e, channel))?;
+            transaction
+                .exec_drop(&revive_query, (channel.as_str(), uuid))
+                .map_err(|e| failure(e, channel))?;
+            transaction
+                .exec_drop(&unbury_query, (target.as_str(), uuid, channel.as_str()))
+                .map_err(|e| failure(e, target))?;
+            // Other channels that buried it there can still replay it
+            let buried: i64 = transaction
+                .exec_first(&buried_query, (target.as_str(), uuid))
+                .map_err(|e| failure(e, target))?
+                .unwrap_or(0);
+            if buried == 0 {
+                transaction
+                    .exec_drop(&remove_query, (uuid,))
+                    .map_err(|e| failure(e, target))?;
+                transaction
+                    .exec_drop(&forget_query, (target.as_str(), uuid))
+                    .map_err(|e| failure(e, target))?;
+            }
+        }
+
+        transaction.commit().map_err(|e| failure(e, channel))?;
+        Ok(copies.len())
+    }
+}
+
+// How many times a message still waiting for the reader was handed to it, or why
+// there's nothing left to do with it. Only a message handed to the reader counts,
+// and while a lease on it runs only the member holding it can answer for the group.
+// The delivery is locked, so a fetch can't hand it out again while an ack or a nack
+// is deciding what happens to it. The reader's ack outlives the message, see settle.
+fn waiting<Q: Queryable>(
+    conn: &mut Q,
+    channel: &ChannelName,
+    reader: &str,
+    consumer: &str,
+    uuid: &str,
+) -> Result<u32> {
+    let queued_query: String = format!(
+        "SELECT COUNT(*) FROM {} WHERE uuid = ? AND processed = '0'",
+        message_table(channel)
+    );
+    let delivery_query: String = format!(
+        r"SELECT attempts, acked, member, visible_at FROM {}
+        WHERE channel = ? AND reader = ? AND message_uuid = ?
+        FOR UPDATE",
+        deliveries_table()
+    );
+
+    let delivery: Option<(u32, u8, String, i64)> = conn
+        .exec_first(delivery_query, (channel.as_str(), reader, uuid))
+        .map_err(|e| failure(e, channel))?;
+    if let Some((_, acked, _, _)) = delivery {
+        if acked != 0 {
+            return Err(ServerError::AlreadyAcked(uuid.to_string()).into());
+        }
+    }
+
+    let queued: i64 = conn
+        .exec_first(queued_query, (uuid,))
+        .map_err(|e| failure(e, channel))?
+        .unwrap_or(0);
+    if queued == 0 {
+        return Err(ServerError::UnknownMessage(uuid.to_string()).into());
+    }
+
+    match delivery {
+        Some((_, _, member, visible_at))
+            if member != consumer && visible_at > super::now_millis() =>
+        {
+            Err(ServerError::UnknownMessage(uuid.to_string()).into())
+        }
+        Some((attempts, _, _, _)) => Ok(attempts),
+        None => Err(ServerError::UnknownMessage(uuid.to_string()).into()),
+    }
+}
+
+// Marks the message delivered once every reader is done with it, each group counts
+// once. Marked the way it always was, the maintenance in check_permission drops it.
+// The acks stay behind until the ack retention runs out, visible_at saying when.
+// Readers settling it at the same time queue on the message row, and the count is a
+// locking read so it sees the acks committed while waiting, not the snapshot.
+fn settle<Q: Queryable>(conn: &mut Q, channel: &ChannelName, uuid: &str) -> Result<()> {
+    let lock_query: String = format!(
+        "SELECT uuid FROM {} WHERE uuid = ? FOR UPDATE",
+        message_table(channel)
+    );
+    let outstanding_query: String = format!(
+        r"SELECT COUNT(*) FROM (
+            SELECT DISTINCT COALESCE(CONCAT('group:', members.group_name), registered.uuid)
+                AS reader
+            FROM {} AS registered
+            LEFT JOIN {} AS members
+                ON members.channel = ? AND members.consumer = registered.uuid
+        ) AS readers
+        WHERE reader NOT IN (
+            SELECT reader FROM {} WHERE channel = ? AND message_uuid = ? AND acked != 0
+            LOCK IN SHARE MODE
+        )",
+        permission_table(channel),
+        members_table(),
+        deliveries_table()
+    );
+    let delivered_query: String = format!(
+        r"UPDATE {} SET processed = '1' WHERE uuid = ?",
+        message_table(channel)
+    );
+    let forget_query: String = format!(
+        "DELETE FROM {} WHERE channel = ? AND message_uuid = ? AND acked = 0",
+        deliveries_table()
+    );
+    let retain_query: String = format!(
+        "UPDATE {} SET visible_at = ? WHERE channel = ? AND message_uuid = ?",
+        deliveries_table()
+    );
+    let unbury_query: String = format!(
+        "DELETE FROM {} WHERE dead_letter = ? AND message_uuid = ?",
+        buried_table()
+    );
+
+    conn.exec_drop(lock_query, (uuid,))
+        .map_err(|e| failure(e, channel))?;
+    let outstanding: i64 = conn
+        .exec_first(outstanding_query, (channel.as_str(), channel.as_str(), uuid))
+        .map_err(|e| failure(e, channel))?
+        .unwrap_or(0);
+    if outstanding == 0 {
+        conn.exec_drop(delivered_query, (uuid,))
+            .map_err(|e| failure(e, channel))?;
+        conn.exec_drop(forget_query, (channel.as_str(), uuid))
+            .map_err(|e| failure(e, channel))?;
+        let until: i64 = super::lease_end(super::ack_retention());
+        conn.exec_drop(retain_query, (until, channel.as_str(), uuid))
+            .map_err(|e| failure(e, channel))?;
+        conn.exec_drop(unbury_query, (channel.as_str(), uuid))
+            .map_err(|e| failure(e, channel))?;
+    }
+    Ok(())
+}
+
+// A reader gave up on the message, a copy goes to the dead-letter channel and the
+// reader is done with it like with an ack
+fn bury<Q: Queryable>(
+    conn: &mut Q,
+    channel: &ChannelName,
+    uuid: &str,
+    target: &ChannelName,
+) -> Result<()> {
+    let copy_query: String = format!(
+        r"INSERT INTO {} (uuid, message_type, message, hash)
+        SELECT uuid, message_type, message, hash FROM {} WHERE uuid = ?
+        ON DUPLICATE KEY UPDATE processed = '0'",
+        message_table(target),
+        message_table(channel)
+    );
+
+    let origin_query: String = format!(
+        "INSERT IGNORE INTO {} (dead_letter, message_uuid, origin) VALUES (?, ?, ?)",
+        buried_table()
+    );
+
+    conn.exec_drop(copy_query, (uuid,))
+        .map_err(|e| failure(e, target))?;
+    conn.exec_drop(origin_query, (target.as_str(), uuid, channel.as_str()))
+        .map_err(|e| failure(e, target))?;
+    settle(conn, channel, uuid)
+}
+
+// The channel's dead-letter channel and how many attempts a message gets first
+fn dead_letter<Q: Queryable>(
+    conn: &mut Q,
+    channel: &ChannelName,
+) -> Result<Option<(ChannelName, u32)>> {
+    let setting_query: String = format!(
+        "SELECT dead_letter, max_attempts FROM {} WHERE channel = ?",
+        dead_letters_table()
+    );
+    let setting: Option<(String, u32)> = conn
+        .exec_first(setting_query, (channel.as_str(),))
+        .map_err(|e| failure(e, channel))?;
+    Ok(setting.and_then(|(target, max_attempts)| {
+        Some((ChannelName::parse(&target)?, max_attempts))
+    }))
+}
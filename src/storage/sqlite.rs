@@ -0,0 +1,635 @@
+use {
+    super::{Fetched, Storage},
+    crate::{
+        error::ServerError,
+        skel::{ChannelName, Message},
+        PROG,
+    },
+    anyhow::{anyhow, Result},
+    logging::append_log,
+    rusqlite::{params, Connection, ErrorCode, OptionalExtension},
+    std::{sync::Mutex, time::Duration},
+};
+
+// A single file database for running the server without a database server. The
+// layout matches the MySQL backend, one message and one permission table per channel
+// and two tables shared by every channel, see ironpulse_deliveries in storage/mod.rs.
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> Result<Self> {
+        let conn: Connection = Connection::open(path)
+            .map_err(|e| anyhow!("Couldn't open sqlite database {}: {}", path, e))?;
+        conn.execute_batch(
+            r"CREATE TABLE IF NOT EXISTS ironpulse_deliveries (
+                channel TEXT NOT NULL,
+                reader TEXT NOT NULL,
+                message_uuid TEXT NOT NULL,
+                member TEXT NOT NULL,
+                visible_at INTEGER NOT NULL DEFAULT 0,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                acked INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (channel, reader, message_uuid)
+            );
+            CREATE TABLE IF NOT EXISTS ironpulse_members (
+                channel TEXT NOT NULL,
+                consumer TEXT NOT NULL,
+                group_name TEXT NOT NULL,
+                PRIMARY KEY (channel, consumer)
+            );
+            CREATE TABLE IF NOT EXISTS ironpulse_dead_letters (
+                channel TEXT NOT NULL PRIMARY KEY,
+                dead_letter TEXT NOT NULL,
+                max_attempts INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS ironpulse_buried (
+                dead_letter TEXT NOT NULL,
+                message_uuid TEXT NOT NULL,
+                origin TEXT NOT NULL,
+                PRIMARY KEY (dead_letter, message_uuid, origin)
+            );",
+        )
+        .map_err(|e| anyhow!("Couldn't create the delivery tables: {}", e))?;
+
+        // Message tables from before hash get it now. Their messages were named by
+        // their hash, so the id they were handed out with stays the same.
+        let unhashed: Vec<String> = conn
+            .prepare(
+                r"SELECT name FROM sqlite_master AS tables
+                WHERE type = 'table'
+                    AND EXISTS (SELECT 1 FROM pragma_table_info(tables.name)
+                        WHERE name = 'processed')
+                    AND NOT EXISTS (SELECT 1 FROM pragma_table_info(tables.name)
+                        WHERE name = 'hash')",
+            )
+            .and_then(|mut statement| statement.query_map([], |row| row.get(0))?.collect())
+            .map_err(|e| anyhow!("Couldn't look for message tables without hash: {}", e))?;
+        for table in unhashed {
+            let add_hash: String = format!(
+                r"ALTER TABLE `{0}` ADD COLUMN hash TEXT NOT NULL DEFAULT '';
+                UPDATE `{0}` SET hash = uuid;",
+                table
+            );
+            conn.execute_batch(&add_hash)
+                .map_err(|e| anyhow!("Couldn't add hash to {}: {}", table, e))?;
+            append_log(PROG, &format!("Added hash to the message table {}", table));
+        }
+        append_log(PROG, &format!("Using sqlite database {}", path));
+        Ok(SqliteStorage {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().expect("Sqlite connection lock poisoned")
+    }
+}
+
+// Sqlite only reports missing and existing tables in the message text, which
+// comes back on a different variant when the statement fails to prepare
+fn failure(e: rusqlite::Error, channel: &ChannelName) -> anyhow::Error {
+    let (code, message): (ErrorCode, &str) = match &e {
+        rusqlite::Error::SqliteFailure(error, message) => {
+            (error.code, message.as_deref().unwrap_or(""))
+        }
+        rusqlite::Error::SqlInputError { error, msg, .. } => (error.code, msg.as_str()),
+        _ => return e.into(),
+    };
+
+    match code {
+        ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => {
+            ServerError::Unavailable(format!("{}", e)).into()
+        }
+        ErrorCode::TooBig => ServerError::TooLarge(format!("{} on {}", e, channel)).into(),
+        _ if message.starts_with("no such table") => {
+            ServerError::ChannelNotFound(channel.to_string()).into()
+        }
+        _ if message.ends_with("already exists") => {
+            ServerError::ChannelExists(channel.to_string()).into()
+        }
+        _ => e.into(),
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn create_channel(&self, channel: &ChannelName) -> Result<()> {
+        let create_query: String = format!(
+            r"CREATE TABLE {} (
+                uuid TEXT NOT NULL PRIMARY KEY,
+                message_type TEXT NOT NULL,
+                message TEXT NOT NULL,
+                hash TEXT NOT NULL,
+                processed INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE {} (
+                uuid TEXT NOT NULL PRIMARY KEY
+            );",
+            channel.message_table(),
+            channel.permission_table()
+        );
+
+        // Both tables or neither
+        let mut conn = self.conn();
+        let transaction = conn.transaction()?;
+        transaction
+            .execute_batch(&create_query)
+            .map_err(|e| failure(e, channel))?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn delete_channel(&self, channel: &ChannelName) -> Result<()> {
+        let drop_query: String = format!(
+            "DROP TABLE {}; DROP TABLE {};",
+            channel.message_table(),
+            channel.permission_table()
+        );
+
+        let mut conn = self.conn();
+        let transaction = conn.transaction()?;
+        transaction
+            .execute_batch(&drop_query)
+            .map_err(|e| failure(e, channel))?;
+        transaction.execute(
+            "DELETE FROM ironpulse_deliveries WHERE channel = ?1",
+            params![channel.as_str()],
+        )?;
+        transaction.execute(
+            "DELETE FROM ironpulse_members WHERE channel = ?1",
+            params![channel.as_str()],
+        )?;
+        transaction.execute(
+            "DELETE FROM ironpulse_dead_letters WHERE channel = ?1 OR dead_letter = ?1",
+            params![channel.as_str()],
+        )?;
+        transaction.execute(
+            "DELETE FROM ironpulse_buried WHERE dead_letter = ?1 OR origin = ?1",
+            params![channel.as_str()],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn set_dead_letter(
+        &self,
+        channel: &ChannelName,
+        dead_letter: Option<(&ChannelName, u32)>,
+    ) -> Result<()> {
+        let conn = self.conn();
+        match dead_letter {
+            Some((target, max_attempts)) => conn.execute(
+                r"INSERT INTO ironpulse_dead_letters (channel, dead_letter, max_attempts)
+                VALUES (?1, ?2, ?3)
+                ON CONFLICT (channel) DO UPDATE SET
+                    dead_letter = excluded.dead_letter,
+                    max_attempts = excluded.max_attempts",
+                params![channel.as_str(), target.as_str(), max_attempts],
+            )?,
+            None => conn.execute(
+                "DELETE FROM ironpulse_dead_letters WHERE channel = ?1",
+                params![channel.as_str()],
+            )?,
+        };
+        Ok(())
+    }
+
+    fn register(&self, channel: &ChannelName, uuid: &str, group: Option<&str>) -> Result<()> {
+        let register_query: String = format!(
+            "INSERT INTO {} (uuid) VALUES (?1)",
+            channel.permission_table()
+        );
+
+        let mut conn = self.conn();
+        let transaction = conn.transaction()?;
+        transaction
+            .execute(&register_query, params![uuid])
+            .map_err(|e| failure(e, channel))?;
+        if let Some(group) = group {
+            transaction.execute(
+                "INSERT INTO ironpulse_members (channel, consumer, group_name) VALUES (?1, ?2, ?3)",
+                params![channel.as_str(), uuid, group],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn check_permission(&self, channel: &ChannelName, uuid: &str) -> Result<bool> {
+        let perm_query: String = format!(
+            "SELECT COUNT(*) FROM {} WHERE uuid = ?1",
+            channel.permission_table()
+        );
+
+        let count: i64 = self
+            .conn()
+            .query_row(&perm_query, params![uuid], |row| row.get(0))
+            .map_err(|e| failure(e, channel))?;
+        Ok(count != 0)
+    }
+
+    fn enqueue(&self, channel: &ChannelName, message: &Message) -> Result<()> {
+        let commit_query: String = format!(
+            "INSERT INTO {} (uuid, message_type, message, hash) VALUES (?1, ?2, ?3, ?4)",
+            channel.message_table()
+        );
+
+        self.conn()
+            .execute(
+                &commit_query,
+                params![message.uuid, message.message_type, message.message, message.hash],
+            )
+            .map_err(|e| failure(e, channel))?;
+        Ok(())
+    }
+
+    fn fetch_many(
+        &self,
+        channel: &ChannelName,
+        consumer: &str,
+        limit: usize,
+        visibility: Duration,
+    ) -> Result<Fetched> {
+        // Never handed to the reader, or handed out and not acked before the lease ran out
+        let check_query: String = format!(
+            r"SELECT uuid, message_type, message, hash, COALESCE(handed.attempts, 0)
+            FROM {} AS queued
+            LEFT JOIN ironpulse_deliveries AS handed
+                ON handed.channel = ?1 AND handed.reader = ?2 AND handed.message_uuid = queued.uuid
+            WHERE queued.processed = 0
+                AND (handed.message_uuid IS NULL OR (handed.acked = 0 AND handed.visible_at <= ?3))
+            ORDER BY queued.rowid LIMIT ?4",
+            channel.message_table()
+        );
+
+        let mut conn = self.conn();
+        let transaction = conn.transaction()?;
+        let reader: String = reader(&transaction, channel, consumer)?;
+        let dead_letter: Option<(ChannelName, u32)> = dead_letter(&transaction, channel)?;
+        let now: i64 = super::now_millis();
+        let visible_at: i64 = super::lease_end(visibility);
+
+        // Buried messages leave room, so read again until none are
+        let (mut handed, mut buried): (Vec<Message>, usize) = (Vec::new(), 0);
+        loop {
+            let candidates: Vec<Message> = transaction
+                .prepare(&check_query)
+                .and_then(|mut statement| {
+                    statement
+                        .query_map(
+                            params![channel.as_str(), reader, now, (limit - handed.len()) as i64],
+                            |row| {
+                                Ok(Message {
+                                    uuid: row.get(0)?,
+                                    message_type: row.get(1)?,
+                                    message: row.get(2)?,
+                                    hash: row.get(3)?,
+                                    attempts: row.get::<_, u32>(4)? + 1,
+                                })
+                            },
+                        )?
+                        .collect()
+                })
+                .map_err(|e| failure(e, channel))?;
+
+            let mut exhausted: bool = false;
+            // One connection, so nobody else in the group can take them in between
+            for message in candidates {
+                match &dead_letter {
+                    Some((target, max_attempts)) if message.attempts > *max_attempts => {
+                        bury(&transaction, channel, &reader, consumer, &message.uuid, target)?;
+                        (buried, exhausted) = (buried + 1, true);
+                    }
+                    _ => {
+                        transaction.execute(
+                            r"INSERT INTO ironpulse_deliveries
+                                (channel, reader, message_uuid, member, visible_at, attempts)
+                            VALUES (?1, ?2, ?3, ?4, ?5, 1)
+                            ON CONFLICT (channel, reader, message_uuid) DO UPDATE SET
+                                member = excluded.member,
+                                visible_at = excluded.visible_at,
+                                attempts = attempts + 1",
+                            params![channel.as_str(), reader, message.uuid, consumer, visible_at],
+                        )?;
+                        handed.push(message);
+                    }
+                }
+            }
+            if !exhausted || handed.len() == limit {
+                break;
+            }
+        }
+
+        transaction.commit()?;
+        Ok(Fetched {
+            messages: handed,
+            dead_letter: dead_letter.filter(|_| buried > 0).map(|(target, _)| target),
+        })
+    }
+
+    fn leased(&self, channel: &ChannelName, consumer: &str) -> Result<usize> {
+        let leased_query: String = format!(
+            r"SELECT COUNT(*) FROM ironpulse_deliveries AS handed
+            JOIN {} AS queued ON queued.uuid = handed.message_uuid
+            WHERE handed.channel = ?1 AND handed.reader = ?2 AND handed.member = ?3
+                AND handed.acked = 0 AND handed.visible_at > ?4 AND queued.processed = 0",
+            channel.message_table()
+        );
+
+        let conn = self.conn();
+        let reader: String = reader(&conn, channel, consumer)?;
+        let held: i64 = conn
+            .query_row(
+                &leased_query,
+                params![channel.as_str(), reader, consumer, super::now_millis()],
+                |row| row.get(0),
+            )
+            .map_err(|e| failure(e, channel))?;
+        Ok(held as usize)
+    }
+
+    fn acknowledge(&self, channel: &ChannelName, consumer: &str, uuid: &str) -> Result<()> {
+        let mut conn = self.conn();
+        let transaction = conn.transaction()?;
+        let reader: String = reader(&transaction, channel, consumer)?;
+        waiting(&transaction, channel, &reader, consumer, uuid)?;
+
+        transaction.execute(
+            r"INSERT INTO ironpulse_deliveries (channel, reader, message_uuid, member, acked)
+            VALUES (?1, ?2, ?3, ?4, 1)
+            ON CONFLICT (channel, reader, message_uuid) DO UPDATE SET acked = 1",
+            params![channel.as_str(), reader, uuid, consumer],
+        )?;
+        settle(&transaction, channel, uuid)?;
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn reject(
+        &self,
+        channel: &ChannelName,
+        consumer: &str,
+        uuid: &str,
+        delay: Duration,
+    ) -> Result<Option<ChannelName>> {
+        let mut conn = self.conn();
+        let transaction = conn.transaction()?;
+        let reader: String = reader(&transaction, channel, consumer)?;
+        let attempts: u32 = waiting(&transaction, channel, &reader, consumer, uuid)?;
+
+        let buried: Option<ChannelName> = match dead_letter(&transaction, channel)? {
+            Some((target, max_attempts)) if attempts >= max_attempts => {
+                bury(&transaction, channel, &reader, consumer, uuid, &target)?;
+                Some(target)
+            }
+            _ => {
+                transaction.execute(
+                    r"INSERT INTO ironpulse_deliveries
+                        (channel, reader, message_uuid, member, visible_at)
+                    VALUES (?1, ?2, ?3, ?4, ?5)
+                    ON CONFLICT (channel, reader, message_uuid) DO UPDATE SET
+                        member = excluded.member,
+                        visible_at = excluded.visible_at",
+                    params![channel.as_str(), reader, uuid, consumer, super::lease_end(delay)],
+                )?;
+                None
+            }
+        };
+
+        transaction.commit()?;
+        Ok(buried)
+    }
+
+    fn dead_letter(&self, channel: &ChannelName) -> Result<Option<(ChannelName, u32)>> {
+        dead_letter(&self.conn(), channel)
+    }
+
+    fn replay(&self, channel: &ChannelName, target: &ChannelName) -> Result<usize> {
+        let mut conn = self.conn();
+        let transaction = conn.transaction()?;
+
+        // Only what this channel buried there
+        let copies_query: String = format!(
+            r"SELECT queued.uuid FROM {} AS queued
+            JOIN ironpulse_buried AS buried
+                ON buried.dead_letter = ?1 AND buried.message_uuid = queued.uuid
+            WHERE buried.origin = ?2 AND queued.processed = 0
+            ORDER BY queued.rowid",
+            target.message_table()
+        );
+        // Back in the channel unless other readers still have it there
+        let restore_query: String = format!(
+            r"INSERT OR IGNORE INTO {} (uuid, message_type, message, hash)
+            SELECT uuid, message_type, message, hash FROM {} WHERE uuid = ?1",
+            channel.message_table(),
+            target.message_table()
+        );
+        let remove_query: String =
+            format!("DELETE FROM {} WHERE uuid = ?1", target.message_table());
+
+        let copies: Vec<String> = transaction
+            .prepare(&copies_query)
+            .and_then(|mut statement| {
+                statement
+                    .query_map(params![target.as_str(), channel.as_str()], |row| row.get(0))?
+                    .collect()
+            })
+            .map_err(|e| failure(e, target))?;
+
+        for uuid in &copies {
+            transaction
+                .execute(&restore_query, params![uuid])
+                .map_err(|e| failure(e, channel))?;
+            // Readers that gave up on it get it again, the others keep their acks
+            transaction.execute(
+                r"DELETE FROM ironpulse_deliveries
+                WHERE channel = ?1 AND message_uuid = ?2 AND acked = 2",
+                params![channel.as_str(), uuid],
+            )?;
+            transaction.execute(
+                r"DELETE FROM ironpulse_buried
+                WHERE dead_letter = ?1 AND message_uuid = ?2 AND origin = ?3",
+                params![target.as_str(), uuid, channel.as_str()],
+            )?;
+            // Other channels that buried it there can still replay it
+            let buried: i64 = transaction.query_row(
+                r"SELECT COUNT(*) FROM ironpulse_buried
+                WHERE dead_letter = ?1 AND message_uuid = ?2",
+                params![target.as_str(), uuid],
+                |row| row.get(0),
+            )?;
+            if buried == 0 {
+                transaction.execute(&remove_query, params![uuid])?;
+                transaction.execute(
+                    "DELETE FROM ironpulse_deliveries WHERE channel = ?1 AND message_uuid = ?2",
+                    params![target.as_str(), uuid],
+                )?;
+            }
+        }
+
+        transaction.commit()?;
+        Ok(copies.len())
+    }
+}
+
+// How many times a message still waiting for the reader was handed to it, or why
+// there's nothing left to do with it. Only a message handed to the reader counts,
+// and while a lease on it runs only the member holding it can answer for the group.
+// The reader's ack outlives the message, see settle.
+fn waiting(
+    conn: &Connection,
+    channel: &ChannelName,
+    reader: &str,
+    consumer: &str,
+    uuid: &str,
+) -> Result<u32> {
+    let queued_query: String = format!(
+        "SELECT COUNT(*) FROM {} WHERE uuid = ?1 AND processed = 0",
+        channel.message_table()
+    );
+
+    let delivery: Option<(u32, i64, String, i64)> = conn
+        .query_row(
+            r"SELECT attempts, acked, member, visible_at FROM ironpulse_deliveries
+            WHERE channel = ?1 AND reader = ?2 AND message_uuid = ?3",
+            params![channel.as_str(), reader, uuid],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .optional()?;
+    if let Some((_, acked, _, _)) = delivery {
+        if acked != 0 {
+            return Err(ServerError::AlreadyAcked(uuid.to_string()).into());
+        }
+    }
+
+    let queued: i64 = conn
+        .query_row(&queued_query, params![uuid], |row| row.get(0))
+        .map_err(|e| failure(e, channel))?;
+    if queued == 0 {
+        return Err(ServerError::UnknownMessage(uuid.to_string()).into());
+    }
+
+    match delivery {
+        Some((_, _, member, visible_at))
+            if member != consumer && visible_at > super::now_millis() =>
+        {
+            Err(ServerError::UnknownMessage(uuid.to_string()).into())
+        }
+        Some((attempts, _, _, _)) => Ok(attempts),
+        None => Err(ServerError::UnknownMessage(uuid.to_string()).into()),
+    }
+}
+
+// Removes the message once every reader is done with it, each group counts once. The
+// acks stay behind until the ack retention runs out, visible_at saying when.
+fn settle(conn: &Connection, channel: &ChannelName, uuid: &str) -> Result<()> {
+    let outstanding_query: String = format!(
+        r"SELECT COUNT(*) FROM (
+            SELECT DISTINCT COALESCE('group:' || members.group_name, registered.uuid) AS reader
+            FROM {} AS registered
+            LEFT JOIN ironpulse_members AS members
+                ON members.channel = ?1 AND members.consumer = registered.uuid
+        )
+        WHERE reader NOT IN (
+            SELECT reader FROM ironpulse_deliveries
+            WHERE channel = ?1 AND message_uuid = ?2 AND acked != 0
+        )",
+        channel.permission_table()
+    );
+    let delivered_query: String =
+        format!("DELETE FROM {} WHERE uuid = ?1", channel.message_table());
+    // Those of messages removed earlier whose retention ran out
+    let expired_query: String = format!(
+        r"DELETE FROM ironpulse_deliveries
+        WHERE channel = ?1 AND acked != 0 AND visible_at <= ?2
+            AND message_uuid NOT IN (SELECT uuid FROM {})",
+        channel.message_table()
+    );
+
+    let outstanding: i64 = conn
+        .query_row(&outstanding_query, params![channel.as_str(), uuid], |row| {
+            row.get(0)
+        })
+        .map_err(|e| failure(e, channel))?;
+    if outstanding == 0 {
+        // Everyone has it, nothing reads it again
+        conn.execute(&delivered_query, params![uuid])?;
+        conn.execute(
+            r"DELETE FROM ironpulse_deliveries
+            WHERE channel = ?1 AND message_uuid = ?2 AND acked = 0",
+            params![channel.as_str(), uuid],
+        )?;
+        conn.execute(
+            r"UPDATE ironpulse_deliveries SET visible_at = ?3
+            WHERE channel = ?1 AND message_uuid = ?2",
+            params![channel.as_str(), uuid, super::lease_end(super::ack_retention())],
+        )?;
+        conn.execute(&expired_query, params![channel.as_str(), super::now_millis()])?;
+        conn.execute(
+            "DELETE FROM ironpulse_buried WHERE dead_letter = ?1 AND message_uuid = ?2",
+            params![channel.as_str(), uuid],
+        )?;
+    }
+    Ok(())
+}
+
+// The reader ran out of attempts on the message, a copy goes to the dead-letter
+// channel and the reader is done with it like with an ack
+fn bury(
+    conn: &Connection,
+    channel: &ChannelName,
+    reader: &str,
+    consumer: &str,
+    uuid: &str,
+    target: &ChannelName,
+) -> Result<()> {
+    let copy_query: String = format!(
+        r"INSERT OR IGNORE INTO {} (uuid, message_type, message, hash)
+        SELECT uuid, message_type, message, hash FROM {} WHERE uuid = ?1",
+        target.message_table(),
+        channel.message_table()
+    );
+
+    conn.execute(&copy_query, params![uuid])
+        .map_err(|e| failure(e, target))?;
+    conn.execute(
+        r"INSERT OR IGNORE INTO ironpulse_buried (dead_letter, message_uuid, origin)
+        VALUES (?1, ?2, ?3)",
+        params![target.as_str(), uuid, channel.as_str()],
+    )?;
+    conn.execute(
+        r"INSERT INTO ironpulse_deliveries (channel, reader, message_uuid, member, acked)
+        VALUES (?1, ?2, ?3, ?4, 2)
+        ON CONFLICT (channel, reader, message_uuid) DO UPDATE SET acked = 2",
+        params![channel.as_str(), reader, uuid, consumer],
+    )?;
+    settle(conn, channel, uuid)
+}
+
+// The channel's dead-letter channel and how many attempts a message gets first
+fn dead_letter(conn: &Connection, channel: &ChannelName) -> Result<Option<(ChannelName, u32)>> {
+    let setting: Option<(String, u32)> = conn
+        .query_row(
+            "SELECT dead_letter, max_attempts FROM ironpulse_dead_letters WHERE channel = ?1",
+            params![channel.as_str()],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    Ok(setting.and_then(|(target, max_attempts)| {
+        Some((ChannelName::parse(&target)?, max_attempts))
+    }))
+}
+
+// Who a delivery is recorded for, the consumer's group if it's in one
+fn reader(conn: &Connection, channel: &ChannelName, consumer: &str) -> Result<String> {
+    let group: Option<String> = conn
+        .query_row(
+            "SELECT group_name FROM ironpulse_members WHERE channel = ?1 AND consumer = ?2",
+            params![channel.as_str(), consumer],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(super::reader(consumer, group.as_deref()))
+}
@@ -0,0 +1,199 @@
+use {
+    crate::{
+        authorize,
+        commands::deliverable,
+        config,
+        error::{ServerError, ServerResult},
+        functions::{ack_dr, ack_ok, channel_name, check_permission, push, respond},
+        notify,
+        skel::{ChannelName, Client, Message, Request, RequestData},
+        storage::{self, with_storage, Fetched},
+        PROG,
+    },
+    logging::append_log,
+    std::{collections::HashMap, sync::Arc, time::Duration},
+    tokio::{sync::watch, task::JoinHandle},
+};
+
+// Subscribe and Unsubscribe change what the connection is sent, so they are
+// answered by the connection itself instead of the command handlers
+pub fn handles(request: &Request) -> bool {
+    let command: &str = match request {
+        Request::Code(data) => &data.command,
+        Request::Data(data) => &data.command,
+    };
+    matches!(command, "Subscribe" | "Unsubscribe")
+}
+
+// The channels one connection is subscribed to, each pushed by its own task.
+// Dropping it with the connection stops them all.
+#[derive(Default)]
+pub struct Subscriptions {
+    tasks: HashMap<String, JoinHandle<()>>,
+}
+
+impl Subscriptions {
+    // Answers the request itself, so a subscription is confirmed before anything is
+    // pushed for it and nothing is pushed once it's cancelled
+    pub async fn request(&mut self, request: &Request, client: &Client) {
+        if let Err(e) = self.change(request, client).await {
+            respond(Err(e), client).await;
+        }
+    }
+
+    async fn change(&mut self, request: &Request, client: &Client) -> ServerResult<()> {
+        authorize(request, client)?;
+        let data: &RequestData = match request {
+            Request::Data(data) => data,
+            Request::Code(data) => {
+                return Err(ServerError::Malformed(format!(
+                    "{} without a channel",
+                    data.command
+                )))
+            }
+        };
+
+        match data.command.as_str() {
+            "Subscribe" => self.subscribe(data, client).await,
+            _ => self.unsubscribe(data, client).await,
+        }
+    }
+
+    // Subscribe/channel or Subscribe/channel_maxinflight, one in flight by default
+    async fn subscribe(&mut self, data: &RequestData, client: &Client) -> ServerResult<()> {
+        let (channel, window): (ChannelName, usize) = match data.args.as_slice() {
+            [channel] => (channel_name(channel)?, 1),
+            [channel, window] => (channel_name(channel)?, in_flight(window)?),
+            args => {
+                return Err(ServerError::Malformed(format!(
+                    "expected 1 or 2 fields in the payload, got {}",
+                    args.len()
+                )))
+            }
+        };
+        check_permission(&channel, &data.requestid).await?;
+
+        respond(Ok(ack_dr()), client).await;
+
+        // Subscribing twice keeps the first subscription
+        self.tasks.retain(|_, task| !task.is_finished());
+        if !self.tasks.contains_key(channel.as_str()) {
+            append_log(
+                PROG,
+                &format!(
+                    "{} subscribed to {} with {} in flight",
+                    data.requestid, channel, window
+                ),
+            );
+            let pusher: Client = Client {
+                sender: client.sender.clone(),
+                tag: None,
+                identity: client.identity.clone(),
+                session: Arc::clone(&client.session),
+                encoding: client.encoding,
+            };
+            let name: String = channel.as_str().to_string();
+            self.tasks.insert(
+                name,
+                tokio::spawn(push_messages(
+                    channel,
+                    data.requestid.to_string(),
+                    window,
+                    pusher,
+                )),
+            );
+        }
+        Ok(())
+    }
+
+    // Nothing more is pushed for the channel once this answers
+    async fn unsubscribe(&mut self, data: &RequestData, client: &Client) -> ServerResult<()> {
+        let channel: ChannelName = match data.args.as_slice() {
+            [channel] => channel_name(channel)?,
+            args => {
+                return Err(ServerError::Malformed(format!(
+                    "expected 1 field in the payload, got {}",
+                    args.len()
+                )))
+            }
+        };
+
+        if let Some(task) = self.tasks.remove(channel.as_str()) {
+            task.abort();
+            let _ = task.await;
+        }
+        respond(Ok(ack_ok()), client).await;
+        Ok(())
+    }
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        for task in self.tasks.values() {
+            task.abort();
+        }
+    }
+}
+
+fn in_flight(window: &str) -> ServerResult<usize> {
+    let limit: usize = config::get().limits.max_in_flight;
+    match window.parse::<usize>() {
+        Ok(window) if (1..=limit).contains(&window) => Ok(window),
+        _ => Err(ServerError::Malformed(format!(
+            "max in flight must be between 1 and {}, got {:?}",
+            limit, window
+        ))),
+    }
+}
+
+// Keeps up to `window` messages leased to the subscriber. Storage stays the source
+// of truth: on every change to the channel the leases still held are counted again,
+// acked ones and ones that ran out make room, and that many more are fetched and
+// pushed. A lease that runs out wakes the channel, so its message goes out again.
+async fn push_messages(channel: ChannelName, consumer: String, window: usize, client: Client) {
+    let mut changes: watch::Receiver<u64> = notify::watch(&channel);
+    let visibility: Duration = storage::visibility();
+
+    loop {
+        let (target, reader): (ChannelName, String) = (channel.clone(), consumer.clone());
+        let fetched = with_storage(move |storage| {
+            let room: usize = window.saturating_sub(storage.leased(&target, &reader)?);
+            match room {
+                0 => Ok(Fetched {
+                    messages: Vec::new(),
+                    dead_letter: None,
+                }),
+                _ => storage.fetch_many(&target, &reader, room, visibility),
+            }
+        });
+        let messages: Vec<Message> = match fetched.await {
+            Ok(fetched) => {
+                if let Some(dead_letter) = &fetched.dead_letter {
+                    notify::wake(dead_letter);
+                }
+                fetched.messages
+            }
+            Err(e) => {
+                // Usually the channel was deleted, the client hears why and
+                // the subscription ends
+                push(&channel, Err(e), &client).await;
+                return;
+            }
+        };
+
+        if !messages.is_empty() {
+            notify::wake_after(&channel, visibility);
+        }
+        for message in messages {
+            push(&channel, deliverable(message), &client).await;
+        }
+
+        if client.sender.is_closed() {
+            return;
+        }
+        if changes.changed().await.is_err() {
+            // The channel was forgotten, the next read says whether it's really gone
+            changes = notify::watch(&channel);
+        }
+    }
+}
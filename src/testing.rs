@@ -0,0 +1,29 @@
+use {
+    crate::{auth, config},
+    std::{env, fs, process, sync::Once},
+};
+
+// The client every test signs as and its secret
+pub const CLIENT: &str = "mailer-01";
+pub const SECRET: &str = "s3cret";
+
+static SETUP: Once = Once::new();
+
+// Config and keys are process wide, the first test to get here loads them
+pub fn setup() {
+    SETUP.call_once(|| {
+        let directory = env::temp_dir().join(format!("ironpulse-tests-{}", process::id()));
+        fs::create_dir_all(&directory).expect("Couldn't create the test directory");
+        let keys = directory.join("keys.yaml");
+        fs::write(&keys, format!("{}: \"{}\"\n", CLIENT, SECRET))
+            .expect("Couldn't write the test keys");
+        let config = directory.join("config.yaml");
+        fs::write(
+            &config,
+            format!("storage:\n  backend: memory\nauth:\n  keys_file: {:?}\n", keys),
+        )
+        .expect("Couldn't write the test config");
+        config::load(config.to_str()).expect("Test config didn't load");
+        auth::init().expect("Test keys didn't load");
+    });
+}
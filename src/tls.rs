@@ -0,0 +1,71 @@
+use {
+    crate::{config, PROG},
+    logging::append_log,
+    openssl::{
+        nid::Nid,
+        ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod, SslVerifyMode},
+    },
+    std::pin::Pin,
+    tokio::net::TcpStream,
+    tokio_openssl::SslStream,
+};
+
+// Builds the acceptor from the tls settings, None when tls is off
+pub fn acceptor() -> Result<Option<SslAcceptor>, String> {
+    let settings = &config::get().tls;
+    if !settings.enabled {
+        return Ok(None);
+    }
+
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())
+        .map_err(|e| format!("Couldn't set up tls: {}", e))?;
+    builder
+        .set_certificate_chain_file(&settings.cert)
+        .map_err(|e| format!("Couldn't load certificate {}: {}", settings.cert, e))?;
+    builder
+        .set_private_key_file(&settings.key, SslFiletype::PEM)
+        .map_err(|e| format!("Couldn't load private key {}: {}", settings.key, e))?;
+    builder
+        .check_private_key()
+        .map_err(|e| format!("Certificate and private key don't match: {}", e))?;
+
+    // Mutual tls, clients present a certificate signed by our ca
+    if !settings.client_ca.is_empty() {
+        builder
+            .set_ca_file(&settings.client_ca)
+            .map_err(|e| format!("Couldn't load client ca {}: {}", settings.client_ca, e))?;
+
+        let mode: SslVerifyMode = match settings.require_client_cert {
+            true => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+            false => SslVerifyMode::PEER,
+        };
+        builder.set_verify(mode);
+    }
+
+    append_log(PROG, "Tls enabled on the listener");
+    Ok(Some(builder.build()))
+}
+
+// Runs the handshake. The second value is the common name of a verified client
+// certificate, which stands in for the client's registration id.
+pub async fn accept(
+    acceptor: &SslAcceptor,
+    tcp_stream: TcpStream,
+) -> Result<(SslStream<TcpStream>, Option<String>), String> {
+    let ssl: Ssl = Ssl::new(acceptor.context()).map_err(|e| e.to_string())?;
+    let mut tls_stream = SslStream::new(ssl, tcp_stream).map_err(|e| e.to_string())?;
+    Pin::new(&mut tls_stream)
+        .accept()
+        .await
+        .map_err(|e| format!("Tls handshake failed: {}", e))?;
+
+    let identity: Option<String> = tls_stream.ssl().peer_certificate().and_then(|cert| {
+        cert.subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .and_then(|name| std::str::from_utf8(name.data().as_slice()).ok())
+            .map(|name| name.to_string())
+    });
+
+    Ok((tls_stream, identity))
+}
@@ -0,0 +1,129 @@
+use {
+    crate::{config, handle_stream, PROG},
+    logging::append_log,
+    std::{
+        fs::{self, DirBuilder},
+        io::ErrorKind,
+        os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        path::{Path, PathBuf},
+        process,
+    },
+    tokio::{
+        net::{UnixListener, UnixStream},
+        sync::watch,
+        task::JoinSet,
+    },
+};
+
+// Binds the socket when it's turned on, so a bad path stops startup like the main
+// listener does. A socket file left behind by a server that didn't shut down
+// cleanly is replaced, one a running server still answers on is not.
+pub fn bind() -> Result<Option<UnixListener>, String> {
+    let settings = &config::get().unix;
+    if !settings.enabled {
+        return Ok(None);
+    }
+
+    let path: &Path = Path::new(&settings.path);
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(format!("{} is in use by another server", settings.path));
+            }
+            fs::remove_file(path).map_err(|e| {
+                format!("Couldn't remove the stale socket {}: {}", settings.path, e)
+            })?;
+        }
+        Ok(_) => return Err(format!("{} exists and isn't a socket", settings.path)),
+        Err(e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => return Err(format!("Couldn't inspect {}: {}", settings.path, e)),
+    }
+
+    // The validated mode always parses
+    let mode: u32 = config::socket_mode(&settings.mode).unwrap_or(0o660);
+    let listener: UnixListener = bind_private(path, mode)?;
+
+    append_log(
+        PROG,
+        &format!("Listening on {} with mode {:o}", settings.path, mode),
+    );
+    Ok(Some(listener))
+}
+
+// Binds inside a directory only we can enter and moves the socket into place once
+// it has its mode, so nobody can connect while it still has the umask's
+fn bind_private(path: &Path, mode: u32) -> Result<UnixListener, String> {
+    let name: String = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let staging: PathBuf = path.with_file_name(format!(".{}.{}", name, process::id()));
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&staging)
+        .map_err(|e| format!("Couldn't create {}: {}", staging.display(), e))?;
+
+    let staged: PathBuf = staging.join(&name);
+    let bound: Result<UnixListener, String> = UnixListener::bind(&staged)
+        .map_err(|e| format!("Couldn't listen on {}: {}", path.display(), e))
+        .and_then(|listener| {
+            fs::set_permissions(&staged, fs::Permissions::from_mode(mode))
+                .map_err(|e| format!("Couldn't set the mode of {}: {}", path.display(), e))?;
+            fs::rename(&staged, path)
+                .map_err(|e| format!("Couldn't move the socket to {}: {}", path.display(), e))?;
+            Ok(listener)
+        });
+
+    // Empty once the socket moved, anything left is from a failed bind
+    if let Err(e) = fs::remove_dir_all(&staging) {
+        append_log(PROG, &format!("Couldn't remove {}: {}", staging.display(), e));
+    }
+    bound
+}
+
+// Accepts until shutdown, then removes the socket file and waits for the open
+// connections to finish
+pub async fn serve(listener: UnixListener, mut shutdown: watch::Receiver<bool>) {
+    let mut connections: JoinSet<()> = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((unix_stream, _)) => {
+                    let identity: Option<String> = peer_identity(&unix_stream);
+                    connections.spawn(handle_stream(unix_stream, identity, shutdown.clone()));
+                }
+                Err(e) => append_log(PROG, &format!("Failed at accepting a unix client: {}", e)),
+            },
+            Some(_) = connections.join_next(), if !connections.is_empty() => (),
+            _ = shutdown.changed() => break,
+        }
+    }
+
+    drop(listener);
+    if let Err(e) = fs::remove_file(&config::get().unix.path) {
+        append_log(PROG, &format!("Couldn't remove the socket file: {}", e));
+    }
+    while connections.join_next().await.is_some() {}
+}
+
+// A uid listed in unix.uids may only send requests as its registration id, the
+// same way a client certificate pins one over tls
+fn peer_identity(unix_stream: &UnixStream) -> Option<String> {
+    let uid: u32 = match unix_stream.peer_cred() {
+        Ok(credentials) => credentials.uid(),
+        Err(e) => {
+            append_log(PROG, &format!("Couldn't read the peer credentials: {}", e));
+            return None;
+        }
+    };
+
+    let identity: Option<String> = config::get().unix.uids.get(&uid).cloned();
+    if let Some(registration_id) = &identity {
+        append_log(
+            PROG,
+            &format!("Unix client uid {} is {}", uid, registration_id),
+        );
+    }
+    identity
+}
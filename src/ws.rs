@@ -0,0 +1,180 @@
+use {
+    crate::{
+        config,
+        functions::respond,
+        handle_request, hello, json, protocol,
+        skel::{Client, Encoding, Request, Session},
+        subscription::{self, Subscriptions},
+        PROG,
+    },
+    futures_util::{SinkExt, StreamExt},
+    logging::append_log,
+    std::{net::SocketAddr, sync::Arc, time::Duration},
+    tokio::{
+        net::{TcpListener, TcpStream},
+        sync::{mpsc, mpsc::Sender, watch},
+        task::JoinSet,
+        time::timeout,
+    },
+    tokio_tungstenite::{
+        accept_async_with_config,
+        tungstenite::{protocol::WebSocketConfig, Message},
+    },
+};
+
+// Binds the listener when it's turned on, so a bad address stops startup like the
+// main listener does
+pub async fn bind() -> Result<Option<TcpListener>, String> {
+    let settings = &config::get().websocket;
+    if !settings.enabled {
+        return Ok(None);
+    }
+
+    let address: SocketAddr = settings
+        .address
+        .parse()
+        .map_err(|e| format!("Bad websocket.address {}: {}", settings.address, e))?;
+    match TcpListener::bind(address).await {
+        Ok(listener) => {
+            append_log(PROG, &format!("WebSocket listener on {}", address));
+            Ok(Some(listener))
+        }
+        Err(e) => Err(format!("Couldn't listen on {}: {}", address, e)),
+    }
+}
+
+// Accepts until shutdown, then waits for the open sockets to close
+pub async fn serve(listener: TcpListener, mut shutdown: watch::Receiver<bool>) {
+    let mut sockets: JoinSet<()> = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((tcp_stream, _)) => {
+                    sockets.spawn(handle_socket(tcp_stream, shutdown.clone()));
+                }
+                Err(e) => append_log(PROG, &format!("Failed at accepting a websocket: {}", e)),
+            },
+            Some(_) = sockets.join_next(), if !sockets.is_empty() => (),
+            _ = shutdown.changed() => break,
+        }
+    }
+
+    drop(listener);
+    while sockets.join_next().await.is_some() {}
+}
+
+async fn handle_socket(tcp_stream: TcpStream, mut shutdown: watch::Receiver<bool>) {
+    // Messages are held to the same limit as frames
+    let limit: usize = config::get().limits.max_frame_size;
+    let settings = WebSocketConfig {
+        max_message_size: Some(limit),
+        max_frame_size: Some(limit),
+        ..Default::default()
+    };
+    // A client that stalls the upgrade would otherwise hold the socket, and the
+    // drain, forever
+    let handshake_timeout = Duration::from_secs(config::get().websocket.handshake_timeout);
+    let accepted = tokio::select! {
+        accepted = timeout(
+            handshake_timeout,
+            accept_async_with_config(tcp_stream, Some(settings)),
+        ) => accepted,
+        _ = shutdown.changed() => return,
+    };
+    let socket = match accepted {
+        Ok(Ok(socket)) => socket,
+        Ok(Err(e)) => {
+            append_log(PROG, &format!("WebSocket handshake failed: {}", e));
+            return;
+        }
+        Err(_) => {
+            append_log(PROG, "WebSocket handshake timed out");
+            return;
+        }
+    };
+    let (mut sink, mut stream) = socket.split();
+
+    // Responses and pushed messages share one writer, like the framed connections
+    let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(config::get().limits.max_pipelined);
+    let writer = tokio::spawn(async move {
+        while let Some(response) = receiver.recv().await {
+            let text: String = String::from_utf8_lossy(&response).into_owned();
+            if let Err(e) = sink.send(Message::Text(text)).await {
+                append_log(
+                    PROG,
+                    &format!("Failed at writing onto the websocket: {}", e),
+                );
+                break;
+            }
+        }
+        let _ = sink.close().await;
+    });
+
+    let mut subscriptions: Subscriptions = Subscriptions::default();
+    // Version 0 until a Hello says otherwise, like the framed connections
+    let mut session: Arc<Session> = Arc::new(Session::default());
+
+    loop {
+        let message = tokio::select! {
+            message = stream.next() => message,
+            _ = shutdown.changed() => break,
+        };
+
+        // Pings are answered by the library, binary messages have no meaning here
+        let text: String = match message {
+            Some(Ok(Message::Text(text))) => text,
+            Some(Ok(Message::Close(_))) | None => break,
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => {
+                append_log(PROG, &format!("Failed at reading the websocket: {}", e));
+                break;
+            }
+        };
+
+        let request: Request = match json::parse_request(&text) {
+            Ok(request) => request,
+            Err(e) => {
+                respond(Err(e), &client(&sender, None, &session)).await;
+                continue;
+            }
+        };
+
+        let tag: Option<String> = match &request {
+            Request::Code(data) => data.tag.clone(),
+            Request::Data(data) => data.tag.clone(),
+        };
+        let client: Client = client(&sender, tag, &session);
+
+        if protocol::is_hello(&request) {
+            if let Some(negotiated) = hello(&request, &client).await {
+                session = Arc::new(negotiated);
+            }
+            continue;
+        }
+
+        match subscription::handles(&request) {
+            true => subscriptions.request(&request, &client).await,
+            false => respond(handle_request(request, &client).await, &client).await,
+        }
+    }
+
+    drop(subscriptions);
+
+    // The writer closes the socket once everything queued has been sent
+    drop(sender);
+    if writer.await.is_err() {
+        append_log(PROG, "WebSocket writer task panicked");
+    }
+}
+
+// WebSocket clients speak JSON and always get reasons
+fn client(sender: &Sender<Vec<u8>>, tag: Option<String>, session: &Arc<Session>) -> Client {
+    Client {
+        sender: sender.clone(),
+        tag,
+        identity: None,
+        session: Arc::clone(session),
+        encoding: Encoding::Json,
+    }
+}